@@ -0,0 +1,250 @@
+// 访问日志
+//
+// 每个请求按 nginx 的 combined 格式写入所属虚拟主机 (或顶层 server) 的 access_log，
+// 5xx 响应同时写入 error_log。日志先写入缓冲区，由后台任务定期刷新到文件；
+// 配置更新后关闭不再使用的日志文件。
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Utc};
+use hyper::{Body, Request, Response};
+
+use crate::vhost::Site;
+use crate::{ConnectionInfo, ServerConfig, CONFIG, CONFIG_VERSION};
+
+// 缓冲区刷新到文件的间隔
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// 已打开的日志文件
+lazy_static::lazy_static! {
+    static ref WRITERS: Mutex<HashMap<String, BufWriter<File>>> = Mutex::new(HashMap::new());
+}
+
+/// 写入访问日志所需的请求信息，在请求交给处理函数之前记录
+pub(crate) struct RequestInfo {
+    remote_addr: String,
+    request_line: String,
+    referer: String,
+    user_agent: String,
+}
+
+impl RequestInfo {
+    pub(crate) fn new(req: &Request<Body>, conn: &ConnectionInfo) -> Self {
+        let header = |name: hyper::header::HeaderName| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("-")
+                .to_string()
+        };
+        let uri = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        RequestInfo {
+            remote_addr: conn.remote_addr.ip().to_string(),
+            request_line: format!("{} {} {:?}", req.method(), uri, req.version()),
+            referer: header(hyper::header::REFERER),
+            user_agent: header(hyper::header::USER_AGENT),
+        }
+    }
+}
+
+/// 记录一个已完成的请求
+pub(crate) fn log(site: &Site, request: &RequestInfo, response: &Response<Body>, elapsed: Duration) {
+    let now = SystemTime::now();
+    let status = response.status().as_u16();
+    let bytes = response
+        .headers()
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("-");
+
+    if !site.access_log.is_empty() {
+        let line = format!(
+            "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\" {:.3}",
+            request.remote_addr,
+            access_time(now),
+            escape(&request.request_line),
+            status,
+            bytes,
+            escape(&request.referer),
+            escape(&request.user_agent),
+            elapsed.as_secs_f64()
+        );
+        write(&site.access_log, &line);
+    }
+
+    if status >= 500 && !site.error_log.is_empty() {
+        let server = if site.name.is_empty() { "-" } else { site.name.as_str() };
+        let line = format!(
+            "{} [error] client: {}, server: {}, request: \"{}\", status: {}",
+            error_time(now),
+            request.remote_addr,
+            server,
+            escape(&request.request_line),
+            status
+        );
+        write(&site.error_log, &line);
+    }
+}
+
+/// 将所有日志缓冲区写入文件
+pub(crate) fn flush() {
+    let mut writers = WRITERS.lock().unwrap();
+    for (path, writer) in writers.iter_mut() {
+        if let Err(e) = writer.flush() {
+            eprintln!("写入日志文件 {} 失败: {}", path, e);
+        }
+    }
+}
+
+/// 定期刷新日志缓冲区，配置更新后关闭不再使用的日志文件
+pub(crate) async fn run() {
+    let mut config_changes = CONFIG_VERSION.subscribe();
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(FLUSH_INTERVAL) => flush(),
+            _ = config_changes.changed() => {
+                let paths = configured_paths(&CONFIG.read().unwrap());
+                close_unused(&paths);
+            }
+        }
+    }
+}
+
+// 配置中顶层 server 与各虚拟主机使用的日志文件
+fn configured_paths(config: &ServerConfig) -> HashSet<String> {
+    std::iter::once(Site::new(config, None))
+        .chain(config.virtual_hosts.iter().map(|vhost| Site::new(config, Some(vhost))))
+        .flat_map(|site| [site.access_log, site.error_log])
+        .filter(|path| !path.is_empty())
+        .collect()
+}
+
+// 写入缓冲区后关闭不在 paths 中的日志文件，例如日志路径已修改或虚拟主机已删除
+fn close_unused(paths: &HashSet<String>) {
+    let mut writers = WRITERS.lock().unwrap();
+    writers.retain(|path, writer| {
+        if paths.contains(path) {
+            return true;
+        }
+        if let Err(e) = writer.flush() {
+            eprintln!("写入日志文件 {} 失败: {}", path, e);
+        }
+        false
+    });
+}
+
+fn write(path: &str, line: &str) {
+    let mut writers = WRITERS.lock().unwrap();
+    if !writers.contains_key(path) {
+        match open(path) {
+            Ok(file) => {
+                writers.insert(path.to_string(), BufWriter::new(file));
+            }
+            Err(e) => {
+                eprintln!("打开日志文件 {} 失败: {}", path, e);
+                return;
+            }
+        }
+    }
+    if let Some(writer) = writers.get_mut(path) {
+        if let Err(e) = writeln!(writer, "{}", line) {
+            eprintln!("写入日志文件 {} 失败: {}", path, e);
+        }
+    }
+}
+
+fn open(path: &str) -> std::io::Result<File> {
+    if let Some(parent) = Path::new(path).parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+// 引号与控制字符转义为 \xHH，避免破坏日志格式
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '"' || c == '\\' || c.is_control() {
+            escaped.push_str(&format!("\\x{:02X}", c as u32));
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+// 访问日志的时间格式，如 17/Oct/2026:02:57:36 +0000
+fn access_time(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).format("%d/%b/%Y:%H:%M:%S +0000").to_string()
+}
+
+// 错误日志的时间格式，如 2026/10/17 02:57:36
+fn error_time(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).format("%Y/%m/%d %H:%M:%S").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_formats() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_792_205_856);
+        assert_eq!(access_time(time), "17/Oct/2026:02:57:36 +0000");
+        assert_eq!(error_time(time), "2026/10/17 02:57:36");
+        // 闰日
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!(access_time(time), "29/Feb/2000:00:00:00 +0000");
+    }
+
+    #[test]
+    fn escapes_quotes_and_control_characters() {
+        assert_eq!(escape("GET /\"a\" HTTP/1.1"), "GET /\\x22a\\x22 HTTP/1.1");
+        assert_eq!(escape("a\nb\\"), "a\\x0Ab\\x5C");
+    }
+
+    #[test]
+    fn unused_log_files_closed_after_reload() {
+        let dir = std::env::temp_dir().join(format!("rust-cool-nginx-access-log-{}", std::process::id()));
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+
+        let mut config = crate::default_config();
+        config.server.access_log = path("main.log");
+        config.server.error_log = path("error.log");
+        config.virtual_hosts = serde_json::from_value(serde_json::json!([
+            {"server_names": ["a.test"], "access_log": path("a.log")},
+            {"server_names": ["b.test"], "access_log": path("b.log")}
+        ]))
+        .unwrap();
+        for name in ["main.log", "error.log", "a.log", "b.log"] {
+            write(&path(name), "line");
+        }
+
+        // 删除虚拟主机 b.test 并修改顶层 server 的 access_log
+        config.virtual_hosts.pop();
+        config.server.access_log = path("main-new.log");
+        let paths = configured_paths(&config);
+        assert_eq!(paths, HashSet::from([path("main-new.log"), path("error.log"), path("a.log")]));
+        close_unused(&paths);
+
+        {
+            let writers = WRITERS.lock().unwrap();
+            assert!(writers.contains_key(&path("error.log")));
+            assert!(writers.contains_key(&path("a.log")));
+            assert!(!writers.contains_key(&path("main.log")));
+            assert!(!writers.contains_key(&path("b.log")));
+        }
+        // 关闭前已写入文件
+        assert_eq!(fs::read_to_string(path("b.log")).unwrap(), "line\n");
+
+        WRITERS.lock().unwrap().retain(|path, _| !Path::new(path).starts_with(&dir));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
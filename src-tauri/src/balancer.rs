@@ -0,0 +1,574 @@
+// 上游负载均衡
+//
+// 按上游服务器组的 load_balancing_algorithm 为每个请求选择一个上游服务器。
+// 每个服务器组的运行状态按组名保存在全局变量中，配置中的服务器列表发生变化时自动重建。
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{UpstreamSection, UpstreamServer};
+
+// 一致性哈希环上每单位权重对应的虚拟节点数
+const VIRTUAL_NODES_PER_WEIGHT: i64 = 160;
+
+/// 服务器权重的上限，避免一致性哈希环过大
+pub(crate) const MAX_WEIGHT: u32 = 100;
+
+// 各上游服务器组的运行状态，按组名索引
+lazy_static::lazy_static! {
+    static ref UPSTREAM_STATE: Mutex<HashMap<String, UpstreamState>> = Mutex::new(HashMap::new());
+}
+
+// 在指定服务器组中查找服务器并修改其状态
+fn with_peer(group: &str, address: &str, f: impl FnOnce(&mut Peer)) {
+    let mut groups = UPSTREAM_STATE.lock().unwrap();
+    if let Some(peer) = groups
+        .get_mut(group)
+        .and_then(|state| state.peers.iter_mut().find(|peer| peer.address == address))
+    {
+        f(peer);
+    }
+}
+
+/// 负载均衡算法
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Algorithm {
+    RoundRobin,
+    LeastConn,
+    IpHash,
+}
+
+impl Algorithm {
+    /// 解析配置中的算法名称，未知名称返回错误
+    pub(crate) fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            // 兼容旧版默认配置中的 "round-robin"
+            "round_robin" | "round-robin" => Ok(Algorithm::RoundRobin),
+            "least_conn" => Ok(Algorithm::LeastConn),
+            "ip_hash" => Ok(Algorithm::IpHash),
+            other => Err(format!(
+                "未知的负载均衡算法: {}，可选值: round_robin, least_conn, ip_hash",
+                other
+            )),
+        }
+    }
+}
+
+/// 被选中的上游服务器
+///
+/// 持有期间该服务器的活动连接数加一，用于 least_conn 算法，
+/// 代理在响应体传输完成后才释放。
+pub(crate) struct SelectedPeer {
+    group: String,
+    pub(crate) address: String,
+    _guard: ActiveGuard,
+}
+
+impl SelectedPeer {
+    /// 请求成功，清零该服务器的连续失败次数
+    pub(crate) fn report_success(&self) {
+        with_peer(&self.group, &self.address, |peer| {
+            peer.fails = 0;
+            peer.down_until = None;
+        });
+    }
+
+    /// 请求失败，连续失败达到 max_fails 次后在 fail_timeout 内不再选择该服务器
+    pub(crate) fn report_failure(&self) {
+        with_peer(&self.group, &self.address, |peer| {
+            peer.fails += 1;
+            // max_fails 为 0 时不统计失败
+            if peer.max_fails > 0 && peer.fails >= peer.max_fails {
+                peer.down_until = Some(Instant::now() + peer.fail_timeout);
+                eprintln!(
+                    "上游服务器 {} 连续失败 {} 次，{:?} 内标记为不可用",
+                    peer.address, peer.fails, peer.fail_timeout
+                );
+            }
+        });
+    }
+}
+
+// 活动连接计数守卫，释放时计数减一
+struct ActiveGuard(Arc<AtomicU64>);
+
+impl ActiveGuard {
+    fn new(active: &Arc<AtomicU64>) -> Self {
+        active.fetch_add(1, Ordering::Relaxed);
+        ActiveGuard(active.clone())
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// 单个上游服务器的运行状态
+struct Peer {
+    address: String,
+    weight: i64,
+    max_fails: u32,
+    fail_timeout: Duration,
+    // 平滑加权轮询使用的当前权重
+    current_weight: i64,
+    // 正在处理的请求数
+    active: Arc<AtomicU64>,
+    // 连续失败次数
+    fails: u32,
+    // 被标记为不可用时，恢复可用的时间
+    down_until: Option<Instant>,
+    // 主动健康检查结果
+    healthy: bool,
+    // 主动健康检查的连续失败/成功次数
+    check_fails: u32,
+    check_passes: u32,
+}
+
+impl Peer {
+    fn is_available(&self, now: Instant) -> bool {
+        self.healthy && !matches!(self.down_until, Some(until) if now < until)
+    }
+}
+
+/// 上游服务器状态，用于监控端点
+#[derive(serde::Serialize)]
+pub(crate) struct PeerStatus {
+    address: String,
+    weight: i64,
+    active_connections: u64,
+    fails: u32,
+    max_fails: u32,
+    healthy: bool,
+    available: bool,
+    // 距离恢复可用的剩余秒数
+    down_remaining_secs: u64,
+}
+
+#[derive(Default)]
+struct UpstreamState {
+    peers: Vec<Peer>,
+    // 一致性哈希环: (哈希值, 服务器下标)，按哈希值排序
+    ring: Vec<(u64, usize)>,
+}
+
+impl UpstreamState {
+    // 服务器地址或权重变化时重建运行状态
+    fn sync(&mut self, servers: &[UpstreamServer]) {
+        let unchanged = self.peers.len() == servers.len()
+            && self.peers.iter().zip(servers).all(|(peer, server)| {
+                peer.address == server.address
+                    && peer.weight == peer_weight(server)
+                    && peer.max_fails == server.max_fails
+                    && peer.fail_timeout == peer_fail_timeout(server)
+            });
+        if unchanged {
+            return;
+        }
+
+        let old_peers = std::mem::take(&mut self.peers);
+        self.peers = servers
+            .iter()
+            .map(|server| {
+                // 保留仍在列表中的服务器的活动连接计数和失败状态，避免重建后归零
+                let old_peer = old_peers.iter().find(|peer| peer.address == server.address);
+                Peer {
+                    address: server.address.clone(),
+                    weight: peer_weight(server),
+                    max_fails: server.max_fails,
+                    fail_timeout: peer_fail_timeout(server),
+                    current_weight: 0,
+                    active: old_peer.map(|peer| peer.active.clone()).unwrap_or_default(),
+                    fails: old_peer.map_or(0, |peer| peer.fails),
+                    down_until: old_peer.and_then(|peer| peer.down_until),
+                    healthy: old_peer.map(|peer| peer.healthy).unwrap_or(true),
+                    check_fails: old_peer.map_or(0, |peer| peer.check_fails),
+                    check_passes: old_peer.map_or(0, |peer| peer.check_passes),
+                }
+            })
+            .collect();
+        self.rebuild_ring();
+    }
+
+    // 为每个服务器按权重在哈希环上放置虚拟节点。
+    // 虚拟节点只与服务器地址有关，增删服务器时只有相邻区间的客户端会被重新映射。
+    fn rebuild_ring(&mut self) {
+        self.ring.clear();
+        for (index, peer) in self.peers.iter().enumerate() {
+            for replica in 0..peer.weight * VIRTUAL_NODES_PER_WEIGHT {
+                let key = format!("{}#{}", peer.address, replica);
+                self.ring.push((hash_key(key.as_bytes()), index));
+            }
+        }
+        self.ring.sort_unstable();
+    }
+
+    // 平滑加权轮询 (与 nginx 的 ngx_http_upstream_get_peer 相同)：
+    // 每轮候选服务器的当前权重加上各自的权重，选出当前权重最大的服务器，
+    // 再将其当前权重减去候选服务器的总权重。
+    fn next_round_robin(&mut self, candidates: &[usize]) -> Option<usize> {
+        let total: i64 = candidates.iter().map(|&index| self.peers[index].weight).sum();
+        let mut best: Option<usize> = None;
+
+        for &index in candidates {
+            self.peers[index].current_weight += self.peers[index].weight;
+            match best {
+                Some(b) if self.peers[b].current_weight >= self.peers[index].current_weight => {}
+                _ => best = Some(index),
+            }
+        }
+
+        let best = best?;
+        self.peers[best].current_weight -= total;
+        Some(best)
+    }
+
+    // 选择 活动连接数/权重 最小的服务器，多个服务器并列时在其中加权轮询
+    fn next_least_conn(&mut self, available: &[usize]) -> Option<usize> {
+        let mut candidates: Vec<usize> = Vec::new();
+        let mut best: Option<(u64, i64)> = None;
+
+        for &index in available {
+            let peer = &self.peers[index];
+            let active = peer.active.load(Ordering::Relaxed);
+            // 比较 active / weight，交叉相乘避免浮点运算
+            let ordering = match best {
+                None => std::cmp::Ordering::Less,
+                Some((best_active, best_weight)) => {
+                    (i128::from(active) * i128::from(best_weight))
+                        .cmp(&(i128::from(best_active) * i128::from(peer.weight)))
+                }
+            };
+            match ordering {
+                std::cmp::Ordering::Less => {
+                    best = Some((active, peer.weight));
+                    candidates.clear();
+                    candidates.push(index);
+                }
+                std::cmp::Ordering::Equal => candidates.push(index),
+                std::cmp::Ordering::Greater => {}
+            }
+        }
+
+        if candidates.len() <= 1 {
+            return candidates.first().copied();
+        }
+        self.next_round_robin(&candidates)
+    }
+
+    // 在哈希环上从客户端 IP 对应的位置顺时针查找第一个可用服务器的虚拟节点，
+    // 服务器不可用时客户端落到环上的下一个服务器
+    fn next_ip_hash(&self, client_ip: IpAddr, available: &[usize]) -> Option<usize> {
+        if self.ring.is_empty() {
+            return None;
+        }
+        let available: HashSet<usize> = available.iter().copied().collect();
+        let hash = hash_key(client_ip.to_string().as_bytes());
+        let position = self.ring.partition_point(|&(point, _)| point < hash);
+        (0..self.ring.len())
+            .map(|offset| self.ring[(position + offset) % self.ring.len()].1)
+            .find(|index| available.contains(index))
+    }
+}
+
+// 权重为 0 的服务器按 1 处理，与 nginx 的默认权重保持一致
+fn peer_weight(server: &UpstreamServer) -> i64 {
+    i64::from(server.weight.max(1))
+}
+
+// 默认值与 nginx 相同，为 10 秒
+fn peer_fail_timeout(server: &UpstreamServer) -> Duration {
+    crate::validated_duration(&server.fail_timeout, Duration::from_secs(10))
+}
+
+// FNV-1a 哈希，再经 splitmix64 混合使相近的键也能均匀分布在环上。
+// 不使用 DefaultHasher，保证同一客户端在重启后仍映射到同一服务器。
+pub(crate) fn hash_key(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+/// 为当前请求从服务器组 `group` 中选择上游服务器
+///
+/// `tried` 为本次请求已尝试过的服务器地址，重试时不会再次选中。
+/// 没有可用服务器时返回 None。
+pub(crate) fn select_upstream(
+    group: &str,
+    upstream: &UpstreamSection,
+    load_balancing: bool,
+    client_ip: IpAddr,
+    tried: &[String],
+) -> Option<SelectedPeer> {
+    if upstream.servers.is_empty() {
+        return None;
+    }
+
+    let mut groups = UPSTREAM_STATE.lock().unwrap();
+    let state = groups.entry(group.to_string()).or_default();
+    state.sync(&upstream.servers);
+
+    let now = Instant::now();
+    let available: Vec<usize> = state
+        .peers
+        .iter()
+        .enumerate()
+        .filter(|(_, peer)| peer.is_available(now) && !tried.contains(&peer.address))
+        .map(|(index, _)| index)
+        .collect();
+
+    let index = if !load_balancing {
+        // 未启用负载均衡时按配置顺序使用第一个可用服务器
+        available.first().copied()?
+    } else {
+        // 配置加载时已校验算法名称，这里解析失败时退回轮询
+        match Algorithm::parse(&upstream.load_balancing_algorithm).unwrap_or(Algorithm::RoundRobin) {
+            Algorithm::RoundRobin => state.next_round_robin(&available)?,
+            Algorithm::LeastConn => state.next_least_conn(&available)?,
+            Algorithm::IpHash => state.next_ip_hash(client_ip, &available)?,
+        }
+    };
+
+    let peer = &state.peers[index];
+    Some(SelectedPeer {
+        group: group.to_string(),
+        address: peer.address.clone(),
+        _guard: ActiveGuard::new(&peer.active),
+    })
+}
+
+/// 各上游服务器组的当前状态，按组名排序
+pub(crate) fn upstream_status() -> BTreeMap<String, Vec<PeerStatus>> {
+    let groups = UPSTREAM_STATE.lock().unwrap();
+    let now = Instant::now();
+    groups
+        .iter()
+        .map(|(group, state)| {
+            let peers = state
+                .peers
+                .iter()
+                .map(|peer| PeerStatus {
+                    address: peer.address.clone(),
+                    weight: peer.weight,
+                    active_connections: peer.active.load(Ordering::Relaxed),
+                    fails: peer.fails,
+                    max_fails: peer.max_fails,
+                    healthy: peer.healthy,
+                    available: peer.is_available(now),
+                    down_remaining_secs: peer
+                        .down_until
+                        .map_or(0, |until| until.saturating_duration_since(now).as_secs()),
+                })
+                .collect();
+            (group.clone(), peers)
+        })
+        .collect()
+}
+
+/// 记录一次主动健康检查的结果
+///
+/// 连续失败 `fails` 次后将服务器移出轮询，连续成功 `passes` 次后恢复。
+pub(crate) fn record_health_check(group: &str, address: &str, ok: bool, fails: u32, passes: u32) {
+    with_peer(group, address, |peer| {
+        if ok {
+            peer.check_fails = 0;
+            peer.check_passes += 1;
+            if !peer.healthy && peer.check_passes >= passes.max(1) {
+                peer.healthy = true;
+                println!("上游服务器 {} 健康检查恢复正常", peer.address);
+            }
+        } else {
+            peer.check_passes = 0;
+            peer.check_fails += 1;
+            if peer.healthy && peer.check_fails >= fails.max(1) {
+                peer.healthy = false;
+                eprintln!("上游服务器 {} 健康检查失败，移出轮询", peer.address);
+            }
+        }
+    });
+}
+
+/// 关闭主动健康检查时，恢复服务器组内所有服务器的健康状态
+pub(crate) fn reset_health_checks(group: &str) {
+    let mut groups = UPSTREAM_STATE.lock().unwrap();
+    if let Some(state) = groups.get_mut(group) {
+        for peer in state.peers.iter_mut() {
+            peer.healthy = true;
+            peer.check_fails = 0;
+            peer.check_passes = 0;
+        }
+    }
+}
+
+/// 按配置同步所有服务器组，移除已删除的组，供后台任务在没有请求时也能更新状态
+pub(crate) fn sync_groups(upstreams: &[(String, UpstreamSection)]) {
+    let mut groups = UPSTREAM_STATE.lock().unwrap();
+    groups.retain(|group, _| upstreams.iter().any(|(name, _)| name == group));
+    for (name, upstream) in upstreams {
+        groups.entry(name.clone()).or_default().sync(&upstream.servers);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstream(algorithm: &str, servers: &[(&str, u32)]) -> UpstreamSection {
+        let servers: Vec<serde_json::Value> = servers
+            .iter()
+            .map(|(address, weight)| {
+                serde_json::json!({"address": address, "weight": weight, "max_fails": 1, "fail_timeout": "10s"})
+            })
+            .collect();
+        serde_json::from_value(serde_json::json!({"load_balancing_algorithm": algorithm, "servers": servers})).unwrap()
+    }
+
+    // 每个测试使用独立的服务器组名，避免并行运行的测试共享状态
+    fn select(group: &str, upstream: &UpstreamSection, client_ip: &str) -> String {
+        select_upstream(group, upstream, true, client_ip.parse().unwrap(), &[])
+            .unwrap()
+            .address
+    }
+
+    #[test]
+    fn weighted_round_robin_is_smooth() {
+        let upstream = upstream("round_robin", &[("a:80", 5), ("b:80", 1), ("c:80", 1)]);
+        let sequence: Vec<String> = (0..7).map(|_| select("test_wrr", &upstream, "10.0.0.1")).collect();
+        assert_eq!(sequence, ["a:80", "a:80", "b:80", "a:80", "c:80", "a:80", "a:80"]);
+    }
+
+    #[test]
+    fn weight_above_limit_rejected() {
+        let mut config = crate::default_config();
+        config.upstream = upstream("round_robin", &[("a:80", MAX_WEIGHT)]);
+        assert!(crate::validate_config(&config).is_ok());
+        config.upstream = upstream("round_robin", &[("a:80", MAX_WEIGHT + 1)]);
+        let error = crate::validate_config(&config).unwrap_err();
+        assert!(error.contains("weight"), "{}", error);
+    }
+
+    #[test]
+    fn ip_hash_maps_same_ip_to_same_upstream() {
+        let upstream = upstream("ip_hash", &[("a:80", 1), ("b:80", 1), ("c:80", 1)]);
+        for client in ["10.0.0.1", "10.0.0.2", "192.168.1.20", "::1"] {
+            let first = select("test_ip_hash", &upstream, client);
+            for _ in 0..10 {
+                assert_eq!(select("test_ip_hash", &upstream, client), first);
+            }
+        }
+        // 不同客户端应分布到多个服务器上
+        let chosen: HashSet<String> = (0..64)
+            .map(|i| select("test_ip_hash", &upstream, &format!("10.1.{}.{}", i / 8, i)))
+            .collect();
+        assert!(chosen.len() > 1);
+    }
+
+    #[test]
+    fn ip_hash_fails_over_when_upstream_down() {
+        let upstream = upstream("ip_hash", &[("a:80", 1), ("b:80", 1), ("c:80", 1)]);
+        let clients: Vec<String> = (0..32).map(|i| format!("172.16.0.{}", i)).collect();
+        let before: Vec<String> = clients.iter().map(|c| select("test_failover", &upstream, c)).collect();
+
+        // max_fails 为 1，失败一次即标记为不可用
+        let down = before[0].clone();
+        let peer = select_upstream("test_failover", &upstream, true, clients[0].parse().unwrap(), &[]).unwrap();
+        assert_eq!(peer.address, down);
+        peer.report_failure();
+
+        for (client, previous) in clients.iter().zip(&before) {
+            let now = select("test_failover", &upstream, client);
+            assert_ne!(now, down);
+            // 其他服务器上的客户端不受影响
+            if *previous != down {
+                assert_eq!(&now, previous);
+            }
+        }
+
+        // 恢复后客户端回到原来的服务器
+        with_peer("test_failover", &down, |peer| peer.down_until = None);
+        assert_eq!(select("test_failover", &upstream, &clients[0]), down);
+    }
+
+    #[test]
+    fn least_conn_skips_peer_with_request_in_flight() {
+        let upstream = upstream("least_conn", &[("a:80", 1), ("b:80", 1)]);
+        let pick = || {
+            select_upstream("test_least_conn", &upstream, true, "10.0.0.1".parse().unwrap(), &[]).unwrap()
+        };
+        let active = |address: &str| {
+            upstream_status()["test_least_conn"]
+                .iter()
+                .find(|peer| peer.address == address)
+                .unwrap()
+                .active_connections
+        };
+
+        // 第一个服务器的请求未完成时选择另一个服务器
+        let first = pick();
+        let second = pick();
+        assert_ne!(first.address, second.address);
+        assert_eq!(active(&first.address), 1);
+        assert_eq!(active(&second.address), 1);
+
+        // 请求成功结束后计数减一，该服务器再次成为连接最少的服务器
+        let address = first.address.clone();
+        first.report_success();
+        drop(first);
+        assert_eq!(active(&address), 0);
+        let third = pick();
+        assert_eq!(third.address, address);
+
+        // 请求失败结束同样减一
+        let address = second.address.clone();
+        second.report_failure();
+        drop(second);
+        assert_eq!(active(&address), 0);
+        drop(third);
+    }
+
+    #[test]
+    fn max_fails_marks_peer_down_until_fail_timeout() {
+        let upstream: UpstreamSection = serde_json::from_value(serde_json::json!({
+            "load_balancing_algorithm": "round_robin",
+            "servers": [
+                {"address": "a:80", "weight": 1, "max_fails": 2, "fail_timeout": "200ms"},
+                {"address": "b:80", "weight": 1, "max_fails": 2, "fail_timeout": "200ms"}
+            ]
+        }))
+        .unwrap();
+        // 排除 b 后只能选中 a
+        let tried = ["b:80".to_string()];
+        let pick_a = || select_upstream("test_max_fails", &upstream, true, "10.0.0.1".parse().unwrap(), &tried);
+
+        // 未达到 max_fails 时仍可选择
+        pick_a().unwrap().report_failure();
+        assert!(pick_a().is_some());
+
+        // 达到 max_fails 后 fail_timeout 内不再选择，请求都转到 b
+        pick_a().unwrap().report_failure();
+        assert!(pick_a().is_none());
+        for _ in 0..4 {
+            assert_eq!(select("test_max_fails", &upstream, "10.0.0.1"), "b:80");
+        }
+
+        // fail_timeout 过后恢复
+        std::thread::sleep(Duration::from_millis(250));
+        assert_eq!(pick_a().unwrap().address, "a:80");
+    }
+
+    #[test]
+    fn no_available_upstream() {
+        let upstream = upstream("ip_hash", &[("a:80", 1)]);
+        let tried = vec!["a:80".to_string()];
+        assert!(select_upstream("test_none", &upstream, true, "10.0.0.1".parse().unwrap(), &tried).is_none());
+    }
+}
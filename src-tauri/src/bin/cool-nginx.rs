@@ -0,0 +1,65 @@
+// 无界面运行服务器，使用与桌面应用相同的配置文件格式
+//
+// 用法: cool-nginx serve [--config <path>]
+// 收到 SIGTERM 或 SIGINT 后停止接受新连接，等待进行中的请求完成后退出。
+
+use std::path::PathBuf;
+use std::process::exit;
+
+const USAGE: &str = "用法: cool-nginx serve [--config <path>]
+
+选项:
+  -c, --config <path>  配置文件路径，默认为当前目录下的 nginx.conf
+  -h, --help           显示帮助";
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("serve") => {}
+        Some("-h") | Some("--help") => {
+            println!("{}", USAGE);
+            return;
+        }
+        Some(command) => {
+            eprintln!("未知命令: {}\n\n{}", command, USAGE);
+            exit(2);
+        }
+        None => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    }
+
+    let mut config_path = PathBuf::from("nginx.conf");
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => match args.next() {
+                Some(path) => config_path = PathBuf::from(path),
+                None => {
+                    eprintln!("{} 需要配置文件路径\n\n{}", arg, USAGE);
+                    exit(2);
+                }
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => match arg.strip_prefix("--config=") {
+                Some(path) => config_path = PathBuf::from(path),
+                None => {
+                    eprintln!("未知参数: {}\n\n{}", arg, USAGE);
+                    exit(2);
+                }
+            },
+        }
+    }
+
+    if let Err(e) = app_lib::load_config(&config_path) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+
+    let server = app_lib::start_server();
+    app_lib::wait_for_signal();
+    server.shutdown();
+}
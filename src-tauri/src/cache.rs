@@ -0,0 +1,1611 @@
+// 反向代理响应缓存
+//
+// 启用 features.cache_enabled 后，反向代理 location 的 GET / HEAD 响应按 Cache-Control 与 Expires
+// 给出的有效期缓存到 cache_path 目录。缓存键由请求方法、协议、Host 与改写前的 URI 组成，
+// 并附加 cache_vary 中列出的请求头部的值，响应的 Vary 包含其他头部时不缓存。
+// 每个条目保存为一个文件，首行为 JSON 格式的元数据，其后是响应体，启动或 cache_path 变更时
+// 扫描目录重建索引。总大小超过 cache_max_size 时淘汰最久未使用的条目，
+// 超过 cache_inactive 未被访问的条目由后台任务删除。
+//
+// 响应带有 X-Cache-Status 头 (HIT、MISS、EXPIRED、REVALIDATED、STALE、BYPASS)。过期的条目带上
+// If-None-Match / If-Modified-Since 向上游确认，上游返回 304 时继续使用。上游失败或其他请求正在
+// 更新同一个条目时，按 cache_use_stale 返回过期的条目。同一个键同时只有一个请求访问上游，
+// 其他请求等待它写入缓存后读取，与 nginx 的 proxy_cache_lock 相同。
+// Windows 上正在被读取的缓存文件不能替换或删除：替换失败时稍后重试，仍然失败时放弃写入或删除该条目，
+// 删除失败的文件由后台任务重试。
+//
+// /api/cache 端点与桌面应用的命令可以列出缓存条目，并按 URL、前缀、通配符清除或全部清除。
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures_util::stream;
+use hyper::body::HttpBody;
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use regex::Regex;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::sync::watch;
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    balancer, parse_duration, proxy, tls, validated_duration, vhost, ClientCertAccess, ConnectionInfo, FeaturesSection,
+    LocationConfig, CONFIG,
+};
+
+// 两次读取配置之间的最长间隔，保证配置变更能及时生效
+const MAX_TICK: Duration = Duration::from_secs(1);
+
+// 有明确有效期时可以缓存的状态码
+const CACHEABLE_STATUS: [u16; 8] = [200, 203, 204, 300, 301, 308, 404, 410];
+
+// 正在写入的临时文件所在的子目录
+const TEMP_DIR: &str = "tmp";
+
+// 替换缓存文件的尝试次数与间隔。Windows 上其他请求正在读取的文件暂时不能替换或删除
+const REPLACE_ATTEMPTS: u32 = 5;
+const REPLACE_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+// 上游返回 304 时用新值替换的头部
+const REVALIDATED_HEADERS: [HeaderName; 5] =
+    [header::CACHE_CONTROL, header::EXPIRES, header::DATE, header::ETAG, header::LAST_MODIFIED];
+
+const X_CACHE_STATUS: &str = "x-cache-status";
+
+// cache_use_stale 可以使用的条件
+const STALE_CONDITIONS: [&str; 9] =
+    ["error", "timeout", "updating", "http_500", "http_502", "http_503", "http_504", "http_403", "http_404"];
+
+lazy_static::lazy_static! {
+    // 缓存索引，由后台任务在启动或 cache_path 变更时重建
+    static ref INDEX: Mutex<Index> = Mutex::new(Index::default());
+    // 正在访问上游的缓存键，获取完成时发送端被丢弃
+    static ref FETCHING: Mutex<HashMap<String, watch::Receiver<()>>> = Mutex::new(HashMap::new());
+    // 删除失败的缓存文件，由后台任务重试
+    static ref PENDING_REMOVALS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+}
+
+// 临时文件的序号
+static TEMP_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// 缓存设置
+#[derive(Clone, Debug)]
+pub(crate) struct Settings {
+    root: PathBuf,
+    max_size: u64,
+    inactive: Duration,
+    vary: Vec<String>,
+    use_stale: Vec<String>,
+    // 等待其他请求获取同一个键的最长时间，超时后自己访问上游
+    lock_timeout: Duration,
+}
+
+impl Settings {
+    /// 未启用缓存时返回 None
+    fn new(features: &FeaturesSection) -> Option<Self> {
+        if !features.cache_enabled {
+            return None;
+        }
+        Some(Settings {
+            root: PathBuf::from(&features.cache_path),
+            max_size: parse_size(&features.cache_max_size).ok()?,
+            inactive: validated_duration(&features.cache_inactive, Duration::from_secs(10 * 60)),
+            vary: features.cache_vary.iter().map(|name| name.trim().to_ascii_lowercase()).collect(),
+            use_stale: features.cache_use_stale.iter().map(|condition| condition.trim().to_ascii_lowercase()).collect(),
+            lock_timeout: validated_duration(&features.cache_lock_timeout, Duration::from_secs(5)),
+        })
+    }
+
+    fn use_stale(&self, condition: &str) -> bool {
+        self.use_stale.iter().any(|c| c == condition)
+    }
+}
+
+/// 校验缓存配置，未启用缓存时不检查
+pub(crate) fn validate(features: &FeaturesSection) -> Result<(), String> {
+    if !features.cache_enabled {
+        return Ok(());
+    }
+    if features.cache_path.trim().is_empty() {
+        return Err("启用缓存时 cache_path 不能为空".to_string());
+    }
+    if parse_size(&features.cache_max_size).map_err(|e| format!("cache_max_size: {}", e))? == 0 {
+        return Err("cache_max_size 必须大于 0".to_string());
+    }
+    parse_duration(&features.cache_inactive).map_err(|e| format!("cache_inactive: {}", e))?;
+    parse_duration(&features.cache_lock_timeout).map_err(|e| format!("cache_lock_timeout: {}", e))?;
+    for name in &features.cache_vary {
+        if name.trim() == "*" || HeaderName::from_bytes(name.trim().as_bytes()).is_err() {
+            return Err(format!("cache_vary 中的头部名称无效: {}", name));
+        }
+    }
+    for condition in &features.cache_use_stale {
+        if !STALE_CONDITIONS.contains(&condition.trim().to_ascii_lowercase().as_str()) {
+            return Err(format!("cache_use_stale 中的条件无效: {}，可用的条件: {:?}", condition, STALE_CONDITIONS));
+        }
+    }
+    Ok(())
+}
+
+// 解析 "100M"、"512k"、"1g" 这样的大小，没有单位时为字节数
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().map_err(|_| format!("无法解析大小: {:?}", value))?;
+    let multiplier: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" => 1,
+        "k" => 1024,
+        "m" => 1024 * 1024,
+        "g" => 1024 * 1024 * 1024,
+        _ => return Err(format!("无法解析大小单位: {:?}", value)),
+    };
+    number.checked_mul(multiplier).ok_or_else(|| format!("大小超出范围: {:?}", value))
+}
+
+/// 代理请求使用缓存的方式
+pub(crate) enum CacheRequest {
+    /// 启用了缓存，但请求不能使用缓存
+    Bypass,
+    Cacheable(CacheKey),
+}
+
+/// 请求的缓存键
+pub(crate) struct CacheKey {
+    key: String,
+    head: bool,
+    settings: Settings,
+}
+
+/// 未启用缓存时返回 None。缓存键使用改写前的 URI，需要在改写请求之前调用
+pub(crate) fn request(req: &Request<Body>, conn: &ConnectionInfo) -> Option<CacheRequest> {
+    let settings = Settings::new(&CONFIG.read().unwrap().features)?;
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Some(CacheRequest::Bypass);
+    }
+    let headers = req.headers();
+    // 带凭据的请求、范围请求与协议升级请求不使用共享缓存
+    if headers.contains_key(header::AUTHORIZATION) || headers.contains_key(header::RANGE) || headers.contains_key(header::UPGRADE) {
+        return Some(CacheRequest::Bypass);
+    }
+    // 客户端要求跳过缓存
+    let pragma_no_cache = headers
+        .get_all(header::PRAGMA)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.to_ascii_lowercase().contains("no-cache"));
+    if pragma_no_cache || cache_control(headers).iter().any(|(name, _)| name == "no-store" || name == "no-cache") {
+        return Some(CacheRequest::Bypass);
+    }
+
+    let scheme = if conn.secure { "https" } else { "http" };
+    let host = vhost::request_host(req, conn).unwrap_or_default();
+    let uri = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let mut key = format!("{} {}://{}{}", req.method(), scheme, host, uri);
+    for name in &settings.vary {
+        let values: Vec<&str> = headers.get_all(name.as_str()).iter().filter_map(|value| value.to_str().ok()).collect();
+        key.push_str(&format!("\n{}: {}", name, values.join(", ")));
+    }
+    Some(CacheRequest::Cacheable(CacheKey {
+        key,
+        head: req.method() == Method::HEAD,
+        settings,
+    }))
+}
+
+/// 转发代理请求。请求可以使用缓存时优先返回未过期的缓存条目，并缓存上游的响应
+pub(crate) async fn forward(
+    mut req: Request<Body>,
+    cache: Option<CacheRequest>,
+    conn: &ConnectionInfo,
+    location: &LocationConfig,
+) -> Response<Body> {
+    let cache = match cache {
+        Some(CacheRequest::Cacheable(cache)) => cache,
+        Some(CacheRequest::Bypass) => {
+            let mut response = proxy::forward(req, conn, location).await;
+            set_cache_status(&mut response, "BYPASS");
+            return response;
+        }
+        None => return proxy::forward(req, conn, location).await,
+    };
+
+    // 没有未过期的条目时，同一个键只由一个请求访问上游，其他请求等待它完成后重新查找
+    let mut waited = false;
+    let (cached, fetch) = loop {
+        let cached = match lookup(&cache).await {
+            Some(cached) if cached.fresh => return cached.response("HIT"),
+            cached => cached,
+        };
+        let mut done = match begin_fetch(&cache.key) {
+            Fetch::Owner(guard) => break (cached, Some(guard)),
+            Fetch::Pending(done) => done,
+        };
+        match cached {
+            // 其他请求正在更新时返回过期的条目
+            Some(cached) if cached.stale_allowed(&cache.settings, "updating") => return cached.response("STALE"),
+            // 等待超时后自己访问上游
+            cached if waited => break (cached, None),
+            _ => {}
+        }
+        let _ = tokio::time::timeout(cache.settings.lock_timeout, done.changed()).await;
+        waited = true;
+    };
+
+    // 客户端的条件请求头不转发，过期的条目带上自己的验证器向上游确认
+    req.headers_mut().remove(header::IF_NONE_MATCH);
+    req.headers_mut().remove(header::IF_MODIFIED_SINCE);
+    if let Some(cached) = &cached {
+        cached.meta.add_validators(req.headers_mut());
+    }
+    let response = proxy::forward(req, conn, location).await;
+
+    let status = match cached {
+        Some(cached) => {
+            if response.status() == StatusCode::NOT_MODIFIED {
+                return revalidated(cached, &cache, &response, fetch);
+            }
+            if cached.stale_allowed(&cache.settings, &failure_condition(&response)) {
+                return cached.response("STALE");
+            }
+            "EXPIRED"
+        }
+        None => "MISS",
+    };
+    let mut response = store(cache, response, fetch).await;
+    set_cache_status(&mut response, status);
+    response
+}
+
+fn set_cache_status(response: &mut Response<Body>, status: &'static str) {
+    response.headers_mut().insert(X_CACHE_STATUS, HeaderValue::from_static(status));
+}
+
+// 上游响应对应的 cache_use_stale 条件
+fn failure_condition(response: &Response<Body>) -> String {
+    match response.extensions().get::<proxy::UpstreamFailure>() {
+        Some(proxy::UpstreamFailure::Error) => "error".to_string(),
+        Some(proxy::UpstreamFailure::Timeout) => "timeout".to_string(),
+        None => format!("http_{}", response.status().as_u16()),
+    }
+}
+
+// 访问上游获取缓存键的方式
+enum Fetch {
+    // 由当前请求访问上游，完成后丢弃
+    Owner(FetchGuard),
+    // 其他请求正在访问上游，发送端被丢弃时表示完成
+    Pending(watch::Receiver<()>),
+}
+
+// 丢弃时移除 FETCHING 中的键并唤醒等待的请求。上游失败、响应不能缓存、写入缓存失败、
+// 客户端断开或请求被取消时都会随所在的值一起丢弃，等待的请求不需要等到超时
+struct FetchGuard {
+    key: String,
+    _done: watch::Sender<()>,
+}
+
+impl Drop for FetchGuard {
+    fn drop(&mut self) {
+        FETCHING.lock().unwrap().remove(&self.key);
+    }
+}
+
+fn begin_fetch(key: &str) -> Fetch {
+    let mut fetching = FETCHING.lock().unwrap();
+    if let Some(done) = fetching.get(key) {
+        return Fetch::Pending(done.clone());
+    }
+    let (done, receiver) = watch::channel(());
+    fetching.insert(key.to_string(), receiver);
+    Fetch::Owner(FetchGuard {
+        key: key.to_string(),
+        _done: done,
+    })
+}
+
+// 索引中的缓存条目
+struct Entry {
+    file: PathBuf,
+    // 缓存文件的大小，包括元数据
+    size: u64,
+    stored: SystemTime,
+    expires: SystemTime,
+    last_access: SystemTime,
+    // 从缓存返回的次数，重启后从 0 开始
+    hits: u64,
+    // 响应要求过期后必须向上游确认，不能返回过期的条目
+    must_revalidate: bool,
+}
+
+#[derive(Default)]
+struct Index {
+    // 索引对应的缓存目录，未启用缓存或正在重建索引时为 None
+    root: Option<PathBuf>,
+    entries: HashMap<String, Entry>,
+    // 所有缓存文件的总大小
+    total: u64,
+}
+
+impl Index {
+    // 加入条目，返回被替换的旧文件
+    fn insert(&mut self, key: String, entry: Entry) -> Option<PathBuf> {
+        self.total += entry.size;
+        let file = entry.file.clone();
+        let old = self.entries.insert(key, entry)?;
+        self.total -= old.size;
+        (old.file != file).then_some(old.file)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.total -= entry.size;
+        Some(entry)
+    }
+
+    // 删除超过 inactive 未被访问的条目，返回需要删除的文件
+    fn remove_inactive(&mut self, inactive: Duration, now: SystemTime) -> Vec<PathBuf> {
+        let inactive: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| now.duration_since(entry.last_access).unwrap_or_default() > inactive)
+            .map(|(key, _)| key.clone())
+            .collect();
+        inactive.iter().filter_map(|key| self.remove(key)).map(|entry| entry.file).collect()
+    }
+
+    // 按最近访问时间淘汰条目直到总大小不超过 max_size，返回需要删除的文件
+    fn evict(&mut self, max_size: u64) -> Vec<PathBuf> {
+        if self.total <= max_size {
+            return Vec::new();
+        }
+        let mut by_access: Vec<(SystemTime, String)> =
+            self.entries.iter().map(|(key, entry)| (entry.last_access, key.clone())).collect();
+        by_access.sort();
+
+        let mut files = Vec::new();
+        for (_, key) in by_access {
+            if self.total <= max_size {
+                break;
+            }
+            if let Some(entry) = self.remove(&key) {
+                files.push(entry.file);
+            }
+        }
+        files
+    }
+}
+
+// 缓存文件首行的元数据
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+struct Meta {
+    key: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    // 响应的生成时间与过期时间，UNIX 时间戳 (秒)
+    stored: u64,
+    expires: u64,
+}
+
+impl Meta {
+    fn new(key: String, status: StatusCode, headers: &HeaderMap, stored: SystemTime, expires: SystemTime) -> Self {
+        let seconds = |time: SystemTime| time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        Meta {
+            key,
+            status: status.as_u16(),
+            headers: headers
+                .iter()
+                .filter(|(name, _)| **name != header::AGE)
+                .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+                .collect(),
+            stored: seconds(stored),
+            expires: seconds(expires),
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK)
+    }
+
+    fn header_map(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+                headers.append(name, value);
+            }
+        }
+        headers
+    }
+
+    fn response(&self, body: Body, now: SystemTime) -> Response<Body> {
+        let mut response = Response::new(body);
+        *response.status_mut() = self.status();
+        *response.headers_mut() = self.header_map();
+        let age = now.duration_since(UNIX_EPOCH + Duration::from_secs(self.stored)).unwrap_or_default();
+        response.headers_mut().insert(header::AGE, HeaderValue::from(age.as_secs()));
+        response
+    }
+
+    // 向上游确认过期条目时使用的条件请求头
+    fn add_validators(&self, headers: &mut HeaderMap) {
+        let stored = self.header_map();
+        if let Some(etag) = stored.get(header::ETAG) {
+            headers.insert(header::IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = stored.get(header::LAST_MODIFIED) {
+            headers.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+        }
+    }
+}
+
+// 从缓存文件读取的条目
+struct Cached {
+    meta: Meta,
+    reader: tokio::io::BufReader<tokio::fs::File>,
+    file: PathBuf,
+    fresh: bool,
+    must_revalidate: bool,
+}
+
+impl Cached {
+    fn response(self, status: &'static str) -> Response<Body> {
+        if let Some(entry) = INDEX.lock().unwrap().entries.get_mut(&self.meta.key) {
+            entry.hits += 1;
+        }
+        let mut response = self.meta.response(Body::wrap_stream(ReaderStream::new(self.reader)), SystemTime::now());
+        set_cache_status(&mut response, status);
+        response
+    }
+
+    // 过期的条目在 condition 下是否可以返回给客户端
+    fn stale_allowed(&self, settings: &Settings, condition: &str) -> bool {
+        !self.must_revalidate && settings.use_stale(condition)
+    }
+}
+
+// 缓存文件路径: <cache_path>/<哈希的后两位>/<哈希>。使用与负载均衡相同的 FNV-1a 哈希，
+// 同一个缓存键在不同的 Rust 版本与重启后都对应同一个文件
+fn entry_path(root: &Path, key: &str) -> PathBuf {
+    let hash = format!("{:016x}", balancer::hash_key(key.as_bytes()));
+    root.join(&hash[14..]).join(hash)
+}
+
+// 查找缓存条目 (包括已过期的条目) 并更新访问时间
+async fn lookup(cache: &CacheKey) -> Option<Cached> {
+    let now = SystemTime::now();
+    let (file, fresh, must_revalidate) = {
+        let mut index = INDEX.lock().unwrap();
+        if index.root.as_ref() != Some(&cache.settings.root) {
+            return None;
+        }
+        let entry = index.entries.get_mut(&cache.key)?;
+        entry.last_access = now;
+        (entry.file.clone(), entry.expires > now, entry.must_revalidate)
+    };
+
+    match open_entry(&file, &cache.key).await {
+        Ok((meta, reader)) => Some(Cached {
+            meta,
+            reader,
+            file,
+            fresh,
+            must_revalidate,
+        }),
+        Err(e) => {
+            eprintln!("读取缓存文件失败: {}，路径: {:?}", e, file);
+            let removed = INDEX.lock().unwrap().remove(&cache.key);
+            delete_files(removed.map(|entry| entry.file).into_iter().collect()).await;
+            None
+        }
+    }
+}
+
+async fn open_entry(path: &Path, key: &str) -> io::Result<(Meta, tokio::io::BufReader<tokio::fs::File>)> {
+    let mut reader = tokio::io::BufReader::new(tokio::fs::File::open(path).await?);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let meta: Meta = serde_json::from_str(&line).map_err(io::Error::other)?;
+    if meta.key != key {
+        return Err(io::Error::other("缓存键不一致"));
+    }
+    Ok((meta, reader))
+}
+
+// 上游确认过期的条目仍然有效，按 304 响应的头部更新条目的有效期并返回缓存的响应体
+fn revalidated(
+    mut cached: Cached,
+    cache: &CacheKey,
+    not_modified: &Response<Body>,
+    fetch: Option<FetchGuard>,
+) -> Response<Body> {
+    let mut headers = cached.meta.header_map();
+    for name in REVALIDATED_HEADERS {
+        if not_modified.headers().contains_key(&name) {
+            headers.remove(&name);
+            for value in not_modified.headers().get_all(&name) {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+    }
+    let now = SystemTime::now();
+    let status = cached.meta.status();
+    if let Some((stored, expires)) = freshness(status, &headers, &cache.settings, now) {
+        cached.meta = Meta::new(cache.key.clone(), status, &headers, stored, expires);
+        if let Some(entry) = INDEX.lock().unwrap().entries.get_mut(&cache.key) {
+            entry.stored = stored;
+            entry.expires = expires;
+            entry.must_revalidate = must_revalidate(&headers);
+        }
+        // 在后台用新的元数据重写缓存文件，完成后再让等待的请求读取
+        let (settings, meta, file) = (cache.settings.clone(), cached.meta.clone(), cached.file.clone());
+        tokio::task::spawn_blocking(move || {
+            update_meta(&settings, &meta, &file);
+            drop(fetch);
+        });
+    }
+    cached.response("REVALIDATED")
+}
+
+// 用新的元数据重写缓存文件。无法重写时删除该条目，之后的请求重新访问上游，
+// 而不是继续返回文件中旧的元数据
+fn update_meta(settings: &Settings, meta: &Meta, file: &Path) {
+    if let Err(e) = rewrite(settings, meta, file) {
+        eprintln!("更新缓存文件失败，已删除该条目: {}，路径: {:?}", e, file);
+        let removed = {
+            let mut index = INDEX.lock().unwrap();
+            match index.entries.get(&meta.key) {
+                Some(entry) if entry.file == file => index.remove(&meta.key),
+                _ => None,
+            }
+        };
+        remove_files(removed.map(|entry| entry.file));
+    }
+}
+
+// 用新的元数据替换缓存文件的首行，响应体不变
+fn rewrite(settings: &Settings, meta: &Meta, file: &Path) -> io::Result<()> {
+    let mut reader = BufReader::new(fs::File::open(file)?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+
+    let temp = temp_path(&settings.root)?;
+    let copied = copy_with_meta(&temp, meta, &mut reader);
+    // 替换前关闭原文件，Windows 上打开的文件不能被替换
+    drop(reader);
+    match copied.and_then(|size| replace_file(&temp, file).map(|_| size)) {
+        Ok(size) => {
+            let mut index = INDEX.lock().unwrap();
+            if let Some(entry) = index.entries.get_mut(&meta.key) {
+                let old = std::mem::replace(&mut entry.size, size);
+                index.total = index.total + size - old;
+            }
+            Ok(())
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&temp);
+            Err(e)
+        }
+    }
+}
+
+// 用临时文件替换缓存文件，失败时稍后重试
+fn replace_file(temp: &Path, file: &Path) -> io::Result<()> {
+    let mut attempt = 1;
+    loop {
+        match fs::rename(temp, file) {
+            Err(e) if attempt < REPLACE_ATTEMPTS && e.kind() != io::ErrorKind::NotFound => {
+                std::thread::sleep(REPLACE_RETRY_DELAY * attempt);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// 写入元数据与 reader 中的响应体，返回文件大小
+fn copy_with_meta(path: &Path, meta: &Meta, reader: &mut impl io::Read) -> io::Result<u64> {
+    let mut writer = io::BufWriter::new(fs::File::create(path)?);
+    serde_json::to_writer(&mut writer, meta).map_err(io::Error::other)?;
+    writer.write_all(b"\n")?;
+    io::copy(reader, &mut writer)?;
+    writer.flush()?;
+    drop(writer);
+    Ok(fs::metadata(path)?.len())
+}
+
+// 创建临时文件所在的目录并返回一个新的临时文件路径
+fn temp_path(root: &Path) -> io::Result<PathBuf> {
+    let dir = root.join(TEMP_DIR);
+    fs::create_dir_all(&dir)?;
+    Ok(dir.join(temp_name()))
+}
+
+fn temp_name() -> String {
+    format!("{}.{}", std::process::id(), TEMP_SEQUENCE.fetch_add(1, Ordering::Relaxed))
+}
+
+// 上游响应可以缓存时，在返回给客户端的同时写入临时文件，响应体完整读取后加入索引
+async fn store(cache: CacheKey, response: Response<Body>, fetch: Option<FetchGuard>) -> Response<Body> {
+    let now = SystemTime::now();
+    let (stored, expires) = match freshness(response.status(), response.headers(), &cache.settings, now) {
+        Some(freshness) => freshness,
+        None => return response,
+    };
+    // 响应体的长度，HEAD 响应没有响应体
+    let length = if cache.head {
+        Some(0)
+    } else {
+        response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+    };
+    if length.is_some_and(|length| length > cache.settings.max_size) {
+        return response;
+    }
+
+    let meta = Meta::new(cache.key.clone(), response.status(), response.headers(), stored, expires);
+    let writer = match Writer::create(cache, &meta, stored, expires, length, fetch).await {
+        Ok(writer) => writer,
+        Err(e) => {
+            eprintln!("创建缓存文件失败: {}", e);
+            return response;
+        }
+    };
+
+    // 没有响应体时不会读取响应体，直接提交
+    if length == Some(0) {
+        tokio::spawn(writer.commit());
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = stream::unfold((body, Some(writer)), |(mut body, mut writer)| async move {
+        match body.data().await {
+            Some(Ok(chunk)) => {
+                if let Some(w) = writer.as_mut() {
+                    if let Err(e) = w.write(&chunk).await {
+                        eprintln!("写入缓存文件失败: {}", e);
+                        writer = None;
+                    }
+                }
+                // 按 Content-Length 发送完毕后不会再读取响应体，读到最后一块时就提交
+                if writer.as_ref().is_some_and(|w| w.complete()) {
+                    if let Some(writer) = writer.take() {
+                        writer.commit().await;
+                    }
+                }
+                Some((Ok(chunk), (body, writer)))
+            }
+            // 响应体不完整时丢弃临时文件
+            Some(Err(e)) => Some((Err(e), (body, None))),
+            None => {
+                if let Some(writer) = writer {
+                    writer.commit().await;
+                }
+                None
+            }
+        }
+    });
+    Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+// 响应可以缓存时返回响应的生成时间与过期时间
+fn freshness(
+    status: StatusCode,
+    headers: &HeaderMap,
+    settings: &Settings,
+    now: SystemTime,
+) -> Option<(SystemTime, SystemTime)> {
+    if !CACHEABLE_STATUS.contains(&status.as_u16()) {
+        return None;
+    }
+    if headers.contains_key(header::SET_COOKIE) {
+        return None;
+    }
+    // Vary 中的头部都必须包含在缓存键中
+    let vary_ok = headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .all(|name| name.is_empty() || settings.vary.contains(&name));
+    if !vary_ok {
+        return None;
+    }
+
+    let directives = cache_control(headers);
+    if directives.iter().any(|(name, _)| name == "no-store" || name == "private" || name == "no-cache") {
+        return None;
+    }
+    let seconds = |name: &str| {
+        directives
+            .iter()
+            .find(|(directive, _)| directive == name)
+            .and_then(|(_, value)| value.parse::<u64>().ok())
+    };
+    let lifetime = match seconds("s-maxage").or_else(|| seconds("max-age")) {
+        Some(seconds) => Duration::from_secs(seconds),
+        None => {
+            let expires = header_date(headers, header::EXPIRES)?;
+            let date = header_date(headers, header::DATE).unwrap_or(now);
+            expires.duration_since(date).unwrap_or_default()
+        }
+    };
+    if lifetime.is_zero() {
+        return None;
+    }
+
+    // 有效期从上游生成响应时开始计算
+    let age = headers
+        .get(header::AGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(0);
+    let stored = now.checked_sub(Duration::from_secs(age)).unwrap_or(now);
+    let expires = stored + lifetime;
+    (expires > now).then_some((stored, expires))
+}
+
+// Cache-Control 中的指令，名称转为小写
+fn cache_control(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|directive| {
+            let (name, value) = directive.split_once('=').unwrap_or((directive, ""));
+            let name = name.trim().to_ascii_lowercase();
+            (!name.is_empty()).then(|| (name, value.trim().trim_matches('"').to_string()))
+        })
+        .collect()
+}
+
+fn must_revalidate(headers: &HeaderMap) -> bool {
+    cache_control(headers)
+        .iter()
+        .any(|(name, _)| name == "must-revalidate" || name == "proxy-revalidate")
+}
+
+fn header_date(headers: &HeaderMap, name: HeaderName) -> Option<SystemTime> {
+    httpdate::parse_http_date(headers.get(name)?.to_str().ok()?).ok()
+}
+
+// 正在写入的缓存文件，没有提交时删除临时文件
+struct Writer {
+    file: Option<tokio::fs::File>,
+    temp: PathBuf,
+    key: String,
+    settings: Settings,
+    stored: SystemTime,
+    expires: SystemTime,
+    // 文件大小，以及响应体已写入与 Content-Length 给出的大小
+    size: u64,
+    body_size: u64,
+    length: Option<u64>,
+    must_revalidate: bool,
+    committed: bool,
+    // 写入完成或放弃时通知等待同一个键的请求
+    _fetch: Option<FetchGuard>,
+}
+
+impl Writer {
+    async fn create(
+        cache: CacheKey,
+        meta: &Meta,
+        stored: SystemTime,
+        expires: SystemTime,
+        length: Option<u64>,
+        fetch: Option<FetchGuard>,
+    ) -> io::Result<Self> {
+        let dir = cache.settings.root.join(TEMP_DIR);
+        tokio::fs::create_dir_all(&dir).await?;
+        let temp = dir.join(temp_name());
+        let mut line = serde_json::to_vec(meta).map_err(io::Error::other)?;
+        line.push(b'\n');
+
+        let mut file = tokio::fs::File::create(&temp).await?;
+        if let Err(e) = file.write_all(&line).await {
+            let _ = tokio::fs::remove_file(&temp).await;
+            return Err(e);
+        }
+        Ok(Writer {
+            file: Some(file),
+            temp,
+            key: cache.key,
+            settings: cache.settings,
+            stored,
+            expires,
+            size: line.len() as u64,
+            body_size: 0,
+            length,
+            must_revalidate: must_revalidate(&meta.header_map()),
+            committed: false,
+            _fetch: fetch,
+        })
+    }
+
+    async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.size += chunk.len() as u64;
+        self.body_size += chunk.len() as u64;
+        if self.size > self.settings.max_size {
+            return Err(io::Error::other("响应体超过 cache_max_size"));
+        }
+        match self.file.as_mut() {
+            Some(file) => file.write_all(chunk).await,
+            None => Ok(()),
+        }
+    }
+
+    fn complete(&self) -> bool {
+        self.length == Some(self.body_size)
+    }
+
+    // 将临时文件移动到缓存文件路径并加入索引
+    async fn commit(mut self) {
+        if let Some(mut file) = self.file.take() {
+            if let Err(e) = file.flush().await {
+                eprintln!("写入缓存文件失败: {}", e);
+                return;
+            }
+        }
+        let path = entry_path(&self.settings.root, &self.key);
+        let result = match path.parent() {
+            Some(dir) => tokio::fs::create_dir_all(dir).await,
+            None => Ok(()),
+        };
+        let result = match result {
+            Ok(()) => {
+                let (temp, file) = (self.temp.clone(), path.clone());
+                tokio::task::spawn_blocking(move || replace_file(&temp, &file))
+                    .await
+                    .unwrap_or_else(|e| Err(io::Error::other(e)))
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            eprintln!("保存缓存文件失败: {}，路径: {:?}", e, path);
+            return;
+        }
+        self.committed = true;
+
+        let entry = Entry {
+            file: path,
+            size: self.size,
+            stored: self.stored,
+            expires: self.expires,
+            last_access: SystemTime::now(),
+            hits: 0,
+            must_revalidate: self.must_revalidate,
+        };
+        let files = {
+            let mut index = INDEX.lock().unwrap();
+            // 正在重建索引时不加入，重建时会读取该文件
+            if index.root.as_ref() != Some(&self.settings.root) {
+                return;
+            }
+            let mut files: Vec<PathBuf> = index.insert(self.key.clone(), entry).into_iter().collect();
+            files.extend(index.evict(self.settings.max_size));
+            files
+        };
+        delete_files(files).await;
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        if !self.committed {
+            let temp = std::mem::take(&mut self.temp);
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => {
+                    runtime.spawn_blocking(move || fs::remove_file(temp));
+                }
+                Err(_) => {
+                    let _ = fs::remove_file(temp);
+                }
+            }
+        }
+    }
+}
+
+// 在阻塞线程池中删除缓存文件，不占用处理请求的工作线程
+async fn delete_files(files: Vec<PathBuf>) {
+    if files.is_empty() {
+        return;
+    }
+    if let Err(e) = tokio::task::spawn_blocking(move || remove_files(files)).await {
+        eprintln!("删除缓存文件失败: {}", e);
+    }
+}
+
+// 删除缓存文件，失败的 (如 Windows 上仍被其他请求打开的文件) 由后台任务重试
+fn remove_files(files: impl IntoIterator<Item = PathBuf>) {
+    for file in files {
+        if let Err(e) = fs::remove_file(&file) {
+            if e.kind() != io::ErrorKind::NotFound {
+                eprintln!("删除缓存文件失败，稍后重试: {}，路径: {:?}", e, file);
+                PENDING_REMOVALS.lock().unwrap().push(file);
+            }
+        }
+    }
+}
+
+// 重试删除之前删除失败的文件，已重新加入索引的文件不再删除
+fn retry_removals() {
+    let pending = std::mem::take(&mut *PENDING_REMOVALS.lock().unwrap());
+    if pending.is_empty() {
+        return;
+    }
+    let pending: Vec<PathBuf> = {
+        let index = INDEX.lock().unwrap();
+        pending
+            .into_iter()
+            .filter(|file| !index.entries.values().any(|entry| entry.file == *file))
+            .collect()
+    };
+    let failed: Vec<PathBuf> = pending
+        .into_iter()
+        .filter(|file| fs::remove_file(file).is_err_and(|e| e.kind() != io::ErrorKind::NotFound))
+        .collect();
+    PENDING_REMOVALS.lock().unwrap().extend(failed);
+}
+
+// 扫描缓存目录重建索引，删除无法读取的缓存文件与上次运行遗留的临时文件。
+// 只处理缓存文件命名格式的文件，cache_path 中的其他文件保持不变
+fn rebuild(root: &Path) -> Index {
+    let mut index = Index {
+        root: Some(root.to_path_buf()),
+        ..Index::default()
+    };
+    let hex = |name: &str, len: usize| name.len() == len && name.bytes().all(|b| b.is_ascii_hexdigit());
+
+    if let Ok(temps) = fs::read_dir(root.join(TEMP_DIR)) {
+        for temp in temps.flatten() {
+            let name = temp.file_name().to_string_lossy().to_string();
+            let numbered = name.split('.').count() == 2 && name.split('.').all(|part| part.bytes().all(|b| b.is_ascii_digit()));
+            if numbered {
+                let _ = fs::remove_file(temp.path());
+            }
+        }
+    }
+
+    let dirs = match fs::read_dir(root) {
+        Ok(dirs) => dirs,
+        Err(_) => return index,
+    };
+    let now = SystemTime::now();
+    for dir in dirs.flatten() {
+        if !hex(&dir.file_name().to_string_lossy(), 2) {
+            continue;
+        }
+        let files = match fs::read_dir(dir.path()) {
+            Ok(files) => files,
+            Err(_) => continue,
+        };
+        for file in files.flatten() {
+            let path = file.path();
+            if !hex(&file.file_name().to_string_lossy(), 16) {
+                continue;
+            }
+            match read_entry(&path) {
+                // 重启后的访问时间从现在开始计算
+                Ok((meta, size)) => {
+                    let entry = Entry {
+                        file: path,
+                        size,
+                        stored: UNIX_EPOCH + Duration::from_secs(meta.stored),
+                        expires: UNIX_EPOCH + Duration::from_secs(meta.expires),
+                        last_access: now,
+                        hits: 0,
+                        must_revalidate: must_revalidate(&meta.header_map()),
+                    };
+                    remove_files(index.insert(meta.key, entry));
+                }
+                Err(e) => {
+                    eprintln!("删除无法读取的缓存文件: {}，路径: {:?}", e, path);
+                    remove_files([path]);
+                }
+            }
+        }
+    }
+    index
+}
+
+fn read_entry(path: &Path) -> io::Result<(Meta, u64)> {
+    let file = fs::File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut line = String::new();
+    BufReader::new(file).read_line(&mut line)?;
+    let meta = serde_json::from_str(&line).map_err(io::Error::other)?;
+    Ok((meta, size))
+}
+
+/// 缓存管理任务：按配置重建索引，删除不活跃的条目并限制缓存总大小，重试删除失败的文件
+pub(crate) async fn run() {
+    loop {
+        if let Err(e) = tokio::task::spawn_blocking(retry_removals).await {
+            eprintln!("删除缓存文件失败: {}", e);
+        }
+        let settings = Settings::new(&CONFIG.read().unwrap().features);
+        match settings {
+            // 未启用缓存时释放索引，重新启用时重建
+            None => *INDEX.lock().unwrap() = Index::default(),
+            Some(settings) => {
+                let current = INDEX.lock().unwrap().root.clone();
+                if current.as_ref() != Some(&settings.root) {
+                    *INDEX.lock().unwrap() = Index::default();
+                    let root = settings.root.clone();
+                    match tokio::task::spawn_blocking(move || rebuild(&root)).await {
+                        Ok(index) => {
+                            println!("缓存索引已加载: {} 个条目，路径: {:?}", index.entries.len(), settings.root);
+                            *INDEX.lock().unwrap() = index;
+                        }
+                        Err(e) => eprintln!("重建缓存索引失败: {}", e),
+                    }
+                }
+                let files = {
+                    let mut index = INDEX.lock().unwrap();
+                    let mut files = index.remove_inactive(settings.inactive, SystemTime::now());
+                    files.extend(index.evict(settings.max_size));
+                    files
+                };
+                delete_files(files).await;
+            }
+        }
+        tokio::time::sleep(MAX_TICK).await;
+    }
+}
+
+/// 缓存条目的信息
+#[derive(serde::Serialize, Clone, Debug)]
+pub(crate) struct EntryInfo {
+    key: String,
+    size: u64,
+    // 距离上游生成响应的秒数
+    age: u64,
+    hits: u64,
+    fresh: bool,
+    // 距离上次访问的秒数
+    idle: u64,
+}
+
+/// 清除缓存的范围，按缓存键中的 URL (不包括请求方法与 Vary 头部) 匹配
+pub(crate) enum Purge {
+    /// URL 相同的条目
+    Key(String),
+    /// URL 匹配通配符 * 的条目，按前缀清除时以 * 结尾
+    Pattern(String),
+    All,
+}
+
+impl Purge {
+    /// key、prefix、pattern 与 all 需要且只能指定一个
+    pub(crate) fn new(key: Option<String>, prefix: Option<String>, pattern: Option<String>, all: bool) -> Result<Self, String> {
+        match (key, prefix, pattern, all) {
+            (Some(key), None, None, false) => Ok(Purge::Key(key)),
+            (None, Some(prefix), None, false) => Ok(Purge::Pattern(format!("{}*", prefix))),
+            (None, None, Some(pattern), false) => Ok(Purge::Pattern(pattern)),
+            (None, None, None, true) => Ok(Purge::All),
+            _ => Err("需要且只能指定 key、prefix、pattern、all 中的一个".to_string()),
+        }
+    }
+}
+
+// 缓存键中的 URL
+fn entry_url(key: &str) -> &str {
+    let line = key.lines().next().unwrap_or("");
+    line.split_once(' ').map(|(_, url)| url).unwrap_or(line)
+}
+
+/// 列出缓存条目，按缓存键排序
+pub(crate) fn entries() -> Result<Vec<EntryInfo>, String> {
+    let now = SystemTime::now();
+    let seconds_since = |time: SystemTime| now.duration_since(time).unwrap_or_default().as_secs();
+    let index = INDEX.lock().unwrap();
+    if index.root.is_none() {
+        return Err("缓存未启用或正在加载".to_string());
+    }
+    let mut entries: Vec<EntryInfo> = index
+        .entries
+        .iter()
+        .map(|(key, entry)| EntryInfo {
+            key: key.clone(),
+            size: entry.size,
+            age: seconds_since(entry.stored),
+            hits: entry.hits,
+            fresh: entry.expires > now,
+            idle: seconds_since(entry.last_access),
+        })
+        .collect();
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok(entries)
+}
+
+/// 清除缓存条目并删除缓存文件，返回清除的条目数
+pub(crate) async fn purge(purge: &Purge) -> Result<usize, String> {
+    let pattern = match purge {
+        Purge::Pattern(pattern) => {
+            let pattern = format!("^{}$", regex::escape(pattern).replace(r"\*", ".*"));
+            Some(Regex::new(&pattern).map_err(|e| format!("无效的通配符: {}", e))?)
+        }
+        _ => None,
+    };
+    let files: Vec<PathBuf> = {
+        let mut index = INDEX.lock().unwrap();
+        if index.root.is_none() {
+            return Err("缓存未启用或正在加载".to_string());
+        }
+        let keys: Vec<String> = index
+            .entries
+            .keys()
+            .filter(|key| match purge {
+                Purge::Key(url) => entry_url(key) == url,
+                Purge::Pattern(_) => pattern.as_ref().is_some_and(|pattern| pattern.is_match(entry_url(key))),
+                Purge::All => true,
+            })
+            .cloned()
+            .collect();
+        keys.iter().filter_map(|key| index.remove(key)).map(|entry| entry.file).collect()
+    };
+    let count = files.len();
+    delete_files(files).await;
+    println!("已清除 {} 个缓存条目", count);
+    Ok(count)
+}
+
+/// 缓存管理端点的访问限制。config_api_client_cert 要求客户端证书或限制了证书主题时按其检查，
+/// 否则只允许本机访问，避免默认配置下任何客户端都能清除缓存
+pub(crate) fn api_allowed(access: &ClientCertAccess, conn: &ConnectionInfo) -> bool {
+    if access.required || !access.allowed_subjects.is_empty() {
+        return tls::client_cert_allowed(access, conn);
+    }
+    match conn.remote_addr.ip() {
+        IpAddr::V4(ip) => ip.is_loopback(),
+        // 同时接受 IPv4 的 IPv6 监听地址上，IPv4 客户端的地址为 ::ffff:127.0.0.1 的形式
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(ip.is_loopback(), |ip| ip.is_loopback()),
+    }
+}
+
+/// 缓存管理端点: GET 列出缓存条目，DELETE 按查询参数 key、prefix、pattern 或 all 清除缓存
+pub(crate) async fn api(req: &Request<Body>) -> Response<Body> {
+    let result = match *req.method() {
+        Method::GET => entries().map(|entries| {
+            let total_size: u64 = entries.iter().map(|entry| entry.size).sum();
+            serde_json::json!({ "entries": entries, "total_size": total_size })
+        }),
+        Method::DELETE => match purge_query(req.uri().query().unwrap_or("")) {
+            Ok(target) => purge(&target).await,
+            Err(e) => Err(e),
+        }
+        .map(|count| serde_json::json!({ "message": format!("已清除 {} 个缓存条目", count), "purged": count })),
+        _ => return json_response(405, serde_json::json!({ "error": "只支持 GET 与 DELETE 请求" })),
+    };
+    match result {
+        Ok(body) => json_response(200, body),
+        Err(e) => {
+            eprintln!("缓存管理请求失败: {}", e);
+            json_response(400, serde_json::json!({ "error": e }))
+        }
+    }
+}
+
+fn purge_query(query: &str) -> Result<Purge, String> {
+    let (mut key, mut prefix, mut pattern, mut all) = (None, None, None, false);
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = percent_encoding::percent_decode_str(value).decode_utf8_lossy().to_string();
+        match name {
+            "key" => key = Some(value),
+            "prefix" => prefix = Some(value),
+            "pattern" => pattern = Some(value),
+            "all" => all = value.is_empty() || value == "true" || value == "1",
+            _ => return Err(format!("未知的参数: {}", name)),
+        }
+    }
+    Purge::new(key, prefix, pattern, all)
+}
+
+fn json_response(status: u16, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    lazy_static::lazy_static! {
+        // 索引是全局的，使用索引的测试依次运行
+        static ref INDEX_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+    }
+
+    // 临时缓存目录，作为索引当前的缓存目录
+    struct TempCache(PathBuf);
+
+    impl TempCache {
+        fn new(name: &str) -> Self {
+            let root = std::env::temp_dir().join(format!("cool-nginx-cache-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            *INDEX.lock().unwrap() = Index {
+                root: Some(root.clone()),
+                ..Index::default()
+            };
+            TempCache(root)
+        }
+
+        fn key(&self, key: &str) -> CacheKey {
+            CacheKey {
+                key: key.to_string(),
+                head: false,
+                settings: Settings {
+                    root: self.0.clone(),
+                    max_size: 1024 * 1024,
+                    inactive: Duration::from_secs(600),
+                    vary: Vec::new(),
+                    use_stale: Vec::new(),
+                    lock_timeout: Duration::from_secs(10),
+                },
+            }
+        }
+
+        fn temp_files(&self) -> usize {
+            fs::read_dir(self.0.join(TEMP_DIR)).map(|dir| dir.count()).unwrap_or(0)
+        }
+    }
+
+    impl Drop for TempCache {
+        fn drop(&mut self) {
+            *INDEX.lock().unwrap() = Index::default();
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn cacheable(body: Body) -> Response<Body> {
+        Response::builder()
+            .header(header::CACHE_CONTROL, "max-age=60")
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(body)
+            .unwrap()
+    }
+
+    #[test]
+    fn entry_path_is_stable() {
+        // 重启或升级 Rust 后同一个缓存键仍使用同一个文件
+        let path = entry_path(Path::new("cache"), "GET http://example.com/app.js");
+        assert_eq!(path, Path::new("cache/84/c25b56607d314684"));
+        assert_ne!(path, entry_path(Path::new("cache"), "GET http://example.com/app.css"));
+    }
+
+    #[tokio::test]
+    async fn stored_response_is_committed() {
+        let _lock = INDEX_LOCK.lock().await;
+        let cache = TempCache::new("commit");
+        let key = "GET http://example.com/commit";
+
+        let response = store(cache.key(key), cacheable(Body::from("hello")), None).await;
+        assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), "hello");
+
+        let file = entry_path(&cache.0, key);
+        let (meta, size) = read_entry(&file).unwrap();
+        assert_eq!(meta.key, key);
+        assert_eq!(INDEX.lock().unwrap().entries[key].size, size);
+        assert_eq!(cache.temp_files(), 0);
+
+        let cached = lookup(&cache.key(key)).await.unwrap();
+        assert!(cached.fresh);
+        let response = cached.response("HIT");
+        assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), "hello");
+    }
+
+    #[tokio::test]
+    async fn failed_rewrite_discards_entry_and_retries_removal() {
+        let _lock = INDEX_LOCK.lock().await;
+        let cache = TempCache::new("rewrite");
+        let key = "GET http://example.com/rewrite";
+        let cache_key = cache.key(key);
+
+        let response = store(cache.key(key), cacheable(Body::from("hello")), None).await;
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let file = entry_path(&cache.0, key);
+        let (meta, _) = read_entry(&file).unwrap();
+
+        update_meta(&cache_key.settings, &meta, &file);
+        assert!(INDEX.lock().unwrap().entries.contains_key(key));
+        assert_eq!(read_entry(&file).unwrap().0.key, key);
+
+        // 无法读取或替换的文件 (用目录代替) 从索引中删除，删除失败的文件稍后重试
+        fs::remove_file(&file).unwrap();
+        fs::create_dir(&file).unwrap();
+        update_meta(&cache_key.settings, &meta, &file);
+        assert!(!INDEX.lock().unwrap().entries.contains_key(key));
+        assert!(PENDING_REMOVALS.lock().unwrap().contains(&file));
+        assert_eq!(cache.temp_files(), 0);
+
+        retry_removals();
+        assert!(PENDING_REMOVALS.lock().unwrap().contains(&file));
+        fs::remove_dir(&file).unwrap();
+        fs::write(&file, "locked").unwrap();
+        retry_removals();
+        assert!(!PENDING_REMOVALS.lock().unwrap().contains(&file));
+        assert!(!file.exists());
+    }
+
+    #[tokio::test]
+    async fn incomplete_response_is_discarded() {
+        let _lock = INDEX_LOCK.lock().await;
+        let cache = TempCache::new("discard");
+        let key = "GET http://example.com/discard";
+
+        let (mut sender, body) = Body::channel();
+        let response = store(cache.key(key), cacheable(body), None).await;
+        sender.send_data("partial".into()).await.unwrap();
+        sender.abort();
+        assert!(hyper::body::to_bytes(response.into_body()).await.is_err());
+
+        // 临时文件在后台删除
+        for _ in 0..50 {
+            if cache.temp_files() == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(cache.temp_files(), 0);
+        assert!(!INDEX.lock().unwrap().entries.contains_key(key));
+        assert!(!entry_path(&cache.0, key).exists());
+    }
+
+    // 本地上游，第一个请求按 first 处理，之后的请求立即返回可以缓存的响应
+    fn spawn_upstream(first: fn() -> Option<Response<Body>>) -> SocketAddr {
+        let requests = Arc::new(AtomicUsize::new(0));
+        crate::test_util::spawn_server(move |_req| {
+            let count = requests.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if count == 0 {
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                    match first() {
+                        Some(response) => return response,
+                        // 一直不返回响应
+                        None => std::future::pending::<()>().await,
+                    }
+                }
+                cacheable(Body::from("fresh"))
+            }
+        })
+    }
+
+    fn location(upstream: SocketAddr) -> LocationConfig {
+        serde_json::from_value(serde_json::json!({
+            "path": "/",
+            "handler": "proxy",
+            "proxy_pass": format!("http://{}", upstream),
+        }))
+        .unwrap()
+    }
+
+    fn conn() -> ConnectionInfo {
+        ConnectionInfo {
+            remote_addr: "127.0.0.1:50000".parse().unwrap(),
+            secure: false,
+            client_cert_subject: None,
+            server_name: None,
+        }
+    }
+
+    fn cached_get(cache: &TempCache, key: &str, upstream: SocketAddr) -> impl std::future::Future<Output = Response<Body>> {
+        let request = Request::get(format!("http://{}/", upstream)).body(Body::empty()).unwrap();
+        let cache = CacheRequest::Cacheable(cache.key(key));
+        async move { forward(request, Some(cache), &conn(), &location(upstream)).await }
+    }
+
+    #[test]
+    fn lock_timeout_is_validated() {
+        let mut features = crate::default_config().features;
+        features.cache_enabled = true;
+        assert_eq!(Settings::new(&features).unwrap().lock_timeout, Duration::from_secs(5));
+        features.cache_lock_timeout = "500ms".to_string();
+        assert!(validate(&features).is_ok());
+        assert_eq!(Settings::new(&features).unwrap().lock_timeout, Duration::from_millis(500));
+        features.cache_lock_timeout = "5 minutes".to_string();
+        assert!(validate(&features).unwrap_err().starts_with("cache_lock_timeout"));
+    }
+
+    #[tokio::test]
+    async fn fetch_guard_wakes_waiters() {
+        let key = "GET http://example.com/guard";
+        let owner = match begin_fetch(key) {
+            Fetch::Owner(owner) => owner,
+            Fetch::Pending(_) => panic!("没有其他请求正在获取"),
+        };
+        let mut done = match begin_fetch(key) {
+            Fetch::Pending(done) => done,
+            Fetch::Owner(_) => panic!("已有请求正在获取"),
+        };
+        drop(owner);
+        assert!(!FETCHING.lock().unwrap().contains_key(key));
+        // 发送端已丢弃，等待立即结束
+        assert!(done.changed().await.is_err());
+        assert!(matches!(begin_fetch(key), Fetch::Owner(_)));
+    }
+
+    #[tokio::test]
+    async fn waiter_continues_when_leader_fails() {
+        let _lock = INDEX_LOCK.lock().await;
+        let cache = TempCache::new("leader-fails");
+        let key = "GET http://example.com/leader-fails";
+        let upstream = spawn_upstream(|| Some(Response::builder().status(500).body(Body::empty()).unwrap()));
+
+        let leader = tokio::spawn(cached_get(&cache, key, upstream));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let started = Instant::now();
+        let waiter = cached_get(&cache, key, upstream).await;
+        // 不需要等到 10 秒的 lock_timeout
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(leader.await.unwrap().status(), 500);
+        assert_eq!(waiter.status(), 200);
+        assert_eq!(waiter.headers()[X_CACHE_STATUS], "MISS");
+    }
+
+    #[tokio::test]
+    async fn waiter_continues_when_leader_cancelled() {
+        let _lock = INDEX_LOCK.lock().await;
+        let cache = TempCache::new("leader-cancelled");
+        let key = "GET http://example.com/leader-cancelled";
+        let upstream = spawn_upstream(|| None);
+
+        let leader = tokio::spawn(cached_get(&cache, key, upstream));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let started = Instant::now();
+        let waiter = tokio::spawn(cached_get(&cache, key, upstream));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // 客户端断开时请求被取消
+        leader.abort();
+        let waiter = waiter.await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(waiter.status(), 200);
+        assert_eq!(hyper::body::to_bytes(waiter.into_body()).await.unwrap(), "fresh");
+    }
+
+    fn api_request(method: Method, query: &str) -> Request<Body> {
+        Request::builder().method(method).uri(format!("/api/cache{}", query)).body(Body::empty()).unwrap()
+    }
+
+    async fn api_json(req: Request<Body>) -> (u16, serde_json::Value) {
+        let response = api(&req).await;
+        let status = response.status().as_u16();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn listed_keys(list: &serde_json::Value) -> Vec<&str> {
+        list["entries"].as_array().unwrap().iter().map(|entry| entry["key"].as_str().unwrap()).collect()
+    }
+
+    // 缓存 host 下的 paths
+    async fn fill(cache: &TempCache, host: &str, paths: &[&str]) {
+        for path in paths {
+            let key = format!("GET http://{}{}", host, path);
+            let response = store(cache.key(&key), cacheable(Body::from(path.to_string())), None).await;
+            hyper::body::to_bytes(response.into_body()).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn api_lists_entries() {
+        let _lock = INDEX_LOCK.lock().await;
+        let cache = TempCache::new("api-list");
+        fill(&cache, "example.com", &["/b.css", "/a.js"]).await;
+        lookup(&cache.key("GET http://example.com/a.js")).await.unwrap().response("HIT");
+
+        let (status, list) = api_json(api_request(Method::GET, "")).await;
+        assert_eq!(status, 200);
+        assert_eq!(listed_keys(&list), vec!["GET http://example.com/a.js", "GET http://example.com/b.css"]);
+        assert_eq!(list["entries"][0]["hits"], 1);
+        assert_eq!(list["entries"][1]["hits"], 0);
+        assert_eq!(list["entries"][0]["fresh"], true);
+        let sizes: u64 = list["entries"].as_array().unwrap().iter().map(|entry| entry["size"].as_u64().unwrap()).sum();
+        assert_eq!(list["total_size"], sizes);
+    }
+
+    #[tokio::test]
+    async fn api_purges_by_key_and_prefix() {
+        let _lock = INDEX_LOCK.lock().await;
+        let cache = TempCache::new("api-purge");
+        fill(&cache, "example.com", &["/app.js", "/app.js?v=2", "/img/a.png", "/img/b.png", "/index.html"]).await;
+        fill(&cache, "other.example", &["/img/a.png"]).await;
+
+        // 按 URL 清除时查询参数不同的条目保留
+        let (status, purged) = api_json(api_request(Method::DELETE, "?key=http%3A%2F%2Fexample.com%2Fapp.js")).await;
+        assert_eq!(status, 200);
+        assert_eq!(purged["purged"], 1);
+        assert!(!entry_path(&cache.0, "GET http://example.com/app.js").exists());
+
+        let (_, purged) = api_json(api_request(Method::DELETE, "?prefix=http://example.com/img/")).await;
+        assert_eq!(purged["purged"], 2);
+        let (_, list) = api_json(api_request(Method::GET, "")).await;
+        assert_eq!(
+            listed_keys(&list),
+            vec!["GET http://example.com/app.js?v=2", "GET http://example.com/index.html", "GET http://other.example/img/a.png"]
+        );
+
+        let (_, purged) = api_json(api_request(Method::DELETE, "?pattern=http://*/img/*")).await;
+        assert_eq!(purged["purged"], 1);
+        let (status, error) = api_json(api_request(Method::DELETE, "?key=a&prefix=b")).await;
+        assert_eq!(status, 400);
+        assert!(error["error"].is_string());
+        let (_, purged) = api_json(api_request(Method::DELETE, "?all=true")).await;
+        assert_eq!(purged["purged"], 2);
+        assert!(INDEX.lock().unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn api_allows_only_loopback_by_default() {
+        let access = ClientCertAccess::default();
+        let from = |addr: &str| ConnectionInfo {
+            remote_addr: addr.parse().unwrap(),
+            ..conn()
+        };
+        assert!(api_allowed(&access, &from("127.0.0.1:50000")));
+        assert!(api_allowed(&access, &from("[::1]:50000")));
+        assert!(api_allowed(&access, &from("[::ffff:127.0.0.1]:50000")));
+        assert!(!api_allowed(&access, &from("192.0.2.10:50000")));
+        assert!(!api_allowed(&access, &from("[::ffff:192.0.2.10]:50000")));
+        assert!(!api_allowed(&access, &from("[2001:db8::1]:50000")));
+
+        // 配置了客户端证书限制时按证书检查，本机访问也需要证书
+        let required = ClientCertAccess {
+            required: true,
+            ..ClientCertAccess::default()
+        };
+        assert!(!api_allowed(&required, &from("127.0.0.1:50000")));
+        let subjects = ClientCertAccess {
+            allowed_subjects: vec!["CN=admin".to_string()],
+            ..ClientCertAccess::default()
+        };
+        assert!(!api_allowed(&subjects, &from("127.0.0.1:50000")));
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_purge_is_rejected() {
+        let _lock = INDEX_LOCK.lock().await;
+        let cache = TempCache::new("api-forbidden");
+        fill(&cache, "example.com", &["/app.js"]).await;
+
+        let remote = ConnectionInfo {
+            remote_addr: "192.0.2.10:50000".parse().unwrap(),
+            ..conn()
+        };
+        for req in [api_request(Method::DELETE, "?all=true"), api_request(Method::GET, "")] {
+            let response = crate::handle_request(req, remote.clone(), "./public".to_string(), "/status".to_string())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 403);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(body, "Forbidden: valid client certificate required");
+        }
+        assert_eq!(INDEX.lock().unwrap().entries.len(), 1);
+
+        let local = api_request(Method::DELETE, "?all=true");
+        let response = crate::handle_request(local, conn(), "./public".to_string(), "/status".to_string())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(INDEX.lock().unwrap().entries.is_empty());
+    }
+}
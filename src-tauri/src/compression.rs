@@ -0,0 +1,628 @@
+// 响应压缩
+//
+// 启用 features.gzip_compression 后，按请求的 Accept-Encoding 选择 br、zstd、gzip 或 deflate
+// 流式压缩静态文件、反向代理与 API 的响应。与 nginx 的 gzip 模块相同：只压缩 gzip_types 中的
+// MIME 类型 (text/html 总是压缩)，跳过小于 gzip_min_length 的响应、已经编码的响应与范围响应，
+// 可压缩的响应都会附加 Vary: Accept-Encoding。
+//
+// 启用 features.gzip_static 后，静态文件旁有预先压缩的 .br / .gz 文件且客户端接受对应编码时，
+// 直接返回预压缩文件，Content-Type 与原文件相同，ETag 按编码区分。返回原文件时同样附加
+// Vary: Accept-Encoding。
+
+use std::fs::Metadata;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder, ZlibEncoder, ZstdEncoder};
+use async_compression::Level;
+use futures_util::TryStreamExt;
+use hyper::body::HttpBody;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Body, Method, Request, Response};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::FeaturesSection;
+
+/// 支持的压缩编码
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    // q 值相同时优先使用排在前面的编码
+    const PREFERRED: [Encoding; 4] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip, Encoding::Deflate];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+/// 压缩设置
+#[derive(Clone, Debug)]
+pub(crate) struct Settings {
+    level: u32,
+    min_length: u64,
+    types: Vec<String>,
+}
+
+impl Settings {
+    /// 未启用压缩时返回 None
+    pub(crate) fn new(features: &FeaturesSection) -> Option<Self> {
+        if !features.gzip_compression {
+            return None;
+        }
+        Some(Settings {
+            level: features.gzip_comp_level,
+            min_length: features.gzip_min_length as u64,
+            types: features.gzip_types.iter().map(|t| t.trim().to_ascii_lowercase()).collect(),
+        })
+    }
+
+    fn compressible_type(&self, content_type: &str) -> bool {
+        let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        mime == "text/html" || self.types.iter().any(|t| t == "*" || *t == mime)
+    }
+}
+
+/// 校验压缩配置
+pub(crate) fn validate(features: &FeaturesSection) -> Result<(), String> {
+    if !(1..=9).contains(&features.gzip_comp_level) {
+        return Err(format!("gzip_comp_level 必须在 1 到 9 之间: {}", features.gzip_comp_level));
+    }
+    for mime in &features.gzip_types {
+        if mime.trim() != "*" && !mime.contains('/') {
+            return Err(format!("gzip_types 中的 MIME 类型无效: {}", mime));
+        }
+    }
+    Ok(())
+}
+
+/// 按 Accept-Encoding 选择编码，取 q 值最高的支持编码，客户端不接受任何支持的编码时返回 None
+pub(crate) fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    accepted(accept_encoding).into_iter().next()
+}
+
+// 客户端接受的支持编码，按 q 值从高到低排列
+fn accepted(accept_encoding: &str) -> Vec<Encoding> {
+    let mut qualities = [None; 4];
+    let mut wildcard = None;
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let mut quality = 1.0f32;
+        for param in params {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            if name.trim().eq_ignore_ascii_case("q") {
+                quality = value.trim().parse().unwrap_or(0.0);
+            }
+        }
+        let coding = if coding == "x-gzip" { "gzip".to_string() } else { coding };
+        if coding == "*" {
+            wildcard = Some(quality);
+        } else if let Some(index) = Encoding::PREFERRED.iter().position(|e| e.name() == coding) {
+            qualities[index] = Some(quality);
+        }
+    }
+
+    let mut accepted: Vec<(Encoding, f32)> = Encoding::PREFERRED
+        .iter()
+        .zip(qualities)
+        .filter_map(|(encoding, quality)| Some((*encoding, quality.or(wildcard)?)))
+        .filter(|(_, quality)| *quality > 0.0)
+        .collect();
+    // 稳定排序，q 值相同时保持 PREFERRED 的顺序
+    accepted.sort_by(|a, b| b.1.total_cmp(&a.1));
+    accepted.into_iter().map(|(encoding, _)| encoding).collect()
+}
+
+/// 按配置压缩响应。accept_encoding 为请求的 Accept-Encoding 头
+pub(crate) fn compress(response: Response<Body>, accept_encoding: Option<&str>, settings: &Settings) -> Response<Body> {
+    if !compressible(&response, settings) {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    add_vary(&mut parts.headers);
+
+    let encoding = match accept_encoding.and_then(negotiate) {
+        Some(encoding) => encoding,
+        None => return Response::from_parts(parts, body),
+    };
+    let headers = &mut parts.headers;
+    headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+    headers.remove(header::CONTENT_LENGTH);
+    // 压缩后的内容不支持按原始字节范围请求
+    headers.remove(header::ACCEPT_RANGES);
+    // 压缩后的内容与原内容不再逐字节相同，强 ETag 改为弱 ETag
+    if let Some(etag) = headers.get(header::ETAG).and_then(|value| value.to_str().ok()) {
+        if etag.starts_with('"') {
+            if let Ok(weak) = HeaderValue::from_str(&format!("W/{}", etag)) {
+                headers.insert(header::ETAG, weak);
+            }
+        }
+    }
+
+    Response::from_parts(parts, encode(body, encoding, settings.level))
+}
+
+// 响应是否可以压缩: 没有编码、不是范围响应、类型在 gzip_types 中且长度不小于 gzip_min_length
+fn compressible(response: &Response<Body>, settings: &Settings) -> bool {
+    let status = response.status();
+    if status.is_informational() || status.as_u16() == 204 || status.as_u16() == 206 || status.as_u16() == 304 {
+        return false;
+    }
+    let headers = response.headers();
+    if headers.contains_key(header::CONTENT_RANGE) {
+        return false;
+    }
+    let encoded = headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| !value.trim().eq_ignore_ascii_case("identity"));
+    if encoded || has_token(headers, header::CACHE_CONTROL, "no-transform") {
+        return false;
+    }
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok());
+    if !content_type.is_some_and(|content_type| settings.compressible_type(content_type)) {
+        return false;
+    }
+    // 没有 Content-Length 头时使用响应体的已知长度，流式响应的长度未知，总是压缩
+    let length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .or_else(|| response.body().size_hint().exact());
+    length.map_or(true, |length| length >= settings.min_length)
+}
+
+fn has_token(headers: &HeaderMap, name: header::HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|item| item.trim().eq_ignore_ascii_case(token))
+}
+
+/// 附加 Vary: Accept-Encoding，已包含时不重复添加
+pub(crate) fn add_vary(headers: &mut HeaderMap) {
+    if !has_token(headers, header::VARY, "accept-encoding") && !has_token(headers, header::VARY, "*") {
+        headers.append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+}
+
+// 边读取边压缩响应体
+fn encode(body: Body, encoding: Encoding, level: u32) -> Body {
+    let reader = StreamReader::new(TryStreamExt::map_err(body, io::Error::other));
+    let level = Level::Precise(level as i32);
+    match encoding {
+        Encoding::Brotli => Body::wrap_stream(ReaderStream::new(BrotliEncoder::with_quality(reader, level))),
+        Encoding::Zstd => Body::wrap_stream(ReaderStream::new(ZstdEncoder::with_quality(reader, level))),
+        Encoding::Gzip => Body::wrap_stream(ReaderStream::new(GzipEncoder::with_quality(reader, level))),
+        // HTTP 的 deflate 编码为 zlib 格式 (RFC 9110)
+        Encoding::Deflate => Body::wrap_stream(ReaderStream::new(ZlibEncoder::with_quality(reader, level))),
+    }
+}
+
+/// gzip_static: 返回预压缩文件的响应。范围请求、客户端不接受 br 与 gzip 或没有预压缩文件时
+/// 返回 None，按原文件处理，原文件的响应需要附加 Vary: Accept-Encoding
+pub(crate) async fn serve_precompressed(req: &Request<Body>, root: &Path) -> Option<Response<Body>> {
+    if (req.method() != Method::GET && req.method() != Method::HEAD) || req.headers().contains_key(header::RANGE) {
+        return None;
+    }
+    let accept_encoding = req.headers().get(header::ACCEPT_ENCODING)?.to_str().ok()?;
+    let path = static_path(root, req.uri().path())?;
+
+    for encoding in accepted(accept_encoding) {
+        let extension = match encoding {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+            _ => continue,
+        };
+        let mut compressed = path.clone().into_os_string();
+        compressed.push(".");
+        compressed.push(extension);
+        let file = match tokio::fs::File::open(&compressed).await {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        let metadata = match file.metadata().await {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => continue,
+        };
+        let body = if req.method() == Method::HEAD {
+            Body::empty()
+        } else {
+            Body::wrap_stream(ReaderStream::new(file))
+        };
+        return Some(precompressed_response(req, &path, &metadata, encoding, body));
+    }
+    None
+}
+
+// 请求路径对应的文件，目录使用其中的 index.html。路径包含 .. 时返回 None
+fn static_path(root: &Path, uri_path: &str) -> Option<PathBuf> {
+    let decoded = percent_encoding::percent_decode_str(uri_path).decode_utf8().ok()?;
+    let mut path = root.to_path_buf();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            _ if segment.contains('\\') || segment.contains('\0') => return None,
+            _ => path.push(segment),
+        }
+    }
+    if decoded.ends_with('/') {
+        path.push("index.html");
+    }
+    Some(path)
+}
+
+fn precompressed_response(
+    req: &Request<Body>,
+    original: &Path,
+    metadata: &Metadata,
+    encoding: Encoding,
+    body: Body,
+) -> Response<Body> {
+    let modified = metadata.modified().ok();
+    // 与 hyper-staticfile 相同的格式，附加编码以区分同一文件的不同编码
+    let etag = modified.map(|modified| {
+        let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+        format!(
+            "W/\"{:x}-{:x}.{:x}-{}\"",
+            metadata.len(),
+            since_epoch.as_secs(),
+            since_epoch.subsec_nanos(),
+            encoding.name()
+        )
+    });
+
+    let mut response = Response::builder()
+        .header(header::CONTENT_ENCODING, encoding.name())
+        .header(header::VARY, "Accept-Encoding");
+    if let Some(etag) = &etag {
+        response = response.header(header::ETAG, etag.as_str());
+    }
+    if let Some(modified) = modified {
+        response = response.header(header::LAST_MODIFIED, httpdate::fmt_http_date(modified));
+    }
+
+    if not_modified(req, etag.as_deref(), modified) {
+        return response.status(304).body(Body::empty()).unwrap();
+    }
+    if let Some(mime) = mime_guess::from_path(original).first() {
+        response = response.header(header::CONTENT_TYPE, mime.as_ref());
+    }
+    response
+        .header(header::CONTENT_LENGTH, metadata.len())
+        .body(body)
+        .unwrap()
+}
+
+// 按 If-None-Match 或 If-Modified-Since 判断客户端缓存是否仍然有效
+fn not_modified(req: &Request<Body>, etag: Option<&str>, modified: Option<std::time::SystemTime>) -> bool {
+    let headers = req.headers();
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH).and_then(|value| value.to_str().ok()) {
+        let etag = match etag {
+            Some(etag) => etag.trim_start_matches("W/"),
+            None => return false,
+        };
+        return if_none_match
+            .split(',')
+            .map(|tag| tag.trim())
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+    let if_modified_since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok());
+    match (if_modified_since, modified) {
+        (Some(since), Some(modified)) => {
+            let seconds = |time: std::time::SystemTime| time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            seconds(modified) <= seconds(since)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder, ZlibDecoder, ZstdDecoder};
+    use tokio::io::AsyncReadExt;
+
+    fn settings() -> Settings {
+        Settings {
+            level: 6,
+            min_length: 1024,
+            types: vec!["text/css".to_string(), "application/json".to_string()],
+        }
+    }
+
+    fn response(content_type: &str, body: Vec<u8>) -> Response<Body> {
+        Response::builder()
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    fn encoding_of(response: &Response<Body>) -> Option<&str> {
+        response.headers().get(header::CONTENT_ENCODING).map(|value| value.to_str().unwrap())
+    }
+
+    fn vary(response: &Response<Body>) -> Vec<&str> {
+        response.headers().get_all(header::VARY).iter().map(|value| value.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn negotiate_by_quality() {
+        assert_eq!(negotiate("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip;q=0.5, br;q=0.8"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip;q=1.0, br;q=0.9"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("deflate, gzip;q=0.5"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("GZIP; Q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("x-gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*"), Some(Encoding::Brotli));
+        assert_eq!(
+            accepted("zstd;q=0.2, *;q=0.5, gzip"),
+            vec![Encoding::Gzip, Encoding::Brotli, Encoding::Deflate, Encoding::Zstd]
+        );
+    }
+
+    #[test]
+    fn negotiate_excludes_zero_quality() {
+        assert_eq!(negotiate("gzip;q=0"), None);
+        assert_eq!(negotiate("*;q=0"), None);
+        assert_eq!(negotiate("br;q=0, gzip;q=0.1"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*, br;q=0"), Some(Encoding::Zstd));
+        assert_eq!(accepted("*;q=0.5, br;q=0, zstd;q=0"), vec![Encoding::Gzip, Encoding::Deflate]);
+        // q 值无效时按 0 处理
+        assert_eq!(negotiate("br;q=abc, gzip;q=0.2"), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn negotiate_identity_and_unknown() {
+        assert_eq!(negotiate(""), None);
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(negotiate("compress, identity;q=1"), None);
+        assert_eq!(negotiate("identity, gzip;q=0.5"), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn skips_short_responses() {
+        let settings = settings();
+        let short = compress(response("text/css", vec![b'a'; 1023]), Some("gzip"), &settings);
+        assert_eq!(encoding_of(&short), None);
+        assert!(vary(&short).is_empty());
+        assert_eq!(short.headers()[header::CONTENT_LENGTH], "1023");
+
+        let long = compress(response("text/css", vec![b'a'; 1024]), Some("gzip"), &settings);
+        assert_eq!(encoding_of(&long), Some("gzip"));
+        assert!(!long.headers().contains_key(header::CONTENT_LENGTH));
+
+        // 长度未知的流式响应总是压缩
+        let (mut sender, body) = Body::channel();
+        drop(sender.try_send_data("a".into()));
+        let streaming = Response::builder().header(header::CONTENT_TYPE, "text/css").body(body).unwrap();
+        assert_eq!(encoding_of(&compress(streaming, Some("gzip"), &settings)), Some("gzip"));
+    }
+
+    #[test]
+    fn compresses_configured_types_only() {
+        let settings = settings();
+        let compressed = |content_type: &str, settings: &Settings| {
+            let response = compress(response(content_type, vec![b'a'; 2048]), Some("br"), settings);
+            encoding_of(&response).is_some()
+        };
+        assert!(compressed("text/css", &settings));
+        assert!(compressed("Application/JSON; charset=utf-8", &settings));
+        // text/html 总是压缩
+        assert!(compressed("text/html; charset=utf-8", &settings));
+        assert!(!compressed("image/png", &settings));
+        assert!(!compressed("text/plain", &settings));
+
+        let all = Settings {
+            types: vec!["*".to_string()],
+            ..settings
+        };
+        assert!(compressed("image/png", &all));
+
+        // 已编码、范围响应与 no-transform 的响应不压缩
+        let mut encoded = response("text/css", vec![b'a'; 2048]);
+        encoded.headers_mut().insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        let encoded = compress(encoded, Some("br"), &all);
+        assert_eq!(encoding_of(&encoded), Some("gzip"));
+        let mut partial = response("text/css", vec![b'a'; 2048]);
+        *partial.status_mut() = hyper::StatusCode::PARTIAL_CONTENT;
+        assert_eq!(encoding_of(&compress(partial, Some("br"), &all)), None);
+        let mut no_transform = response("text/css", vec![b'a'; 2048]);
+        no_transform.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("public, no-transform"));
+        assert_eq!(encoding_of(&compress(no_transform, Some("br"), &all)), None);
+    }
+
+    #[test]
+    fn adds_vary_accept_encoding() {
+        let settings = settings();
+        // 客户端不接受压缩时也需要 Vary，缓存不能把未压缩的响应返回给接受压缩的客户端
+        let identity = compress(response("text/css", vec![b'a'; 2048]), None, &settings);
+        assert_eq!(encoding_of(&identity), None);
+        assert_eq!(vary(&identity), vec!["Accept-Encoding"]);
+
+        let mut origin = response("text/css", vec![b'a'; 2048]);
+        origin.headers_mut().insert(header::VARY, HeaderValue::from_static("Origin"));
+        assert_eq!(vary(&compress(origin, Some("gzip"), &settings)), vec!["Origin", "Accept-Encoding"]);
+
+        let mut existing = response("text/css", vec![b'a'; 2048]);
+        existing.headers_mut().insert(header::VARY, HeaderValue::from_static("origin, accept-encoding"));
+        assert_eq!(vary(&compress(existing, Some("gzip"), &settings)), vec!["origin, accept-encoding"]);
+    }
+
+    #[test]
+    fn strong_etag_becomes_weak() {
+        let settings = settings();
+        let with_etag = |etag: &'static str, accept_encoding: Option<&str>| {
+            let mut response = response("text/css", vec![b'a'; 2048]);
+            response.headers_mut().insert(header::ETAG, HeaderValue::from_static(etag));
+            let response = compress(response, accept_encoding, &settings);
+            response.headers()[header::ETAG].to_str().unwrap().to_string()
+        };
+        assert_eq!(with_etag("\"abc\"", Some("gzip")), "W/\"abc\"");
+        assert_eq!(with_etag("W/\"abc\"", Some("gzip")), "W/\"abc\"");
+        // 未压缩时保持原 ETag
+        assert_eq!(with_etag("\"abc\"", None), "\"abc\"");
+    }
+
+    #[tokio::test]
+    async fn compressed_body_decodes_to_original() {
+        let original: Vec<u8> = (0..100_000u32).flat_map(|i| format!("line {}\n", i % 997).into_bytes()).collect();
+        for encoding in Encoding::PREFERRED {
+            let response = compress(response("text/css", original.clone()), Some(encoding.name()), &settings());
+            assert_eq!(encoding_of(&response), Some(encoding.name()));
+            let compressed = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert!(compressed.len() < original.len() / 2);
+
+            let mut decoded = Vec::new();
+            let reader = &compressed[..];
+            match encoding {
+                Encoding::Brotli => BrotliDecoder::new(reader).read_to_end(&mut decoded).await,
+                Encoding::Zstd => ZstdDecoder::new(reader).read_to_end(&mut decoded).await,
+                Encoding::Gzip => GzipDecoder::new(reader).read_to_end(&mut decoded).await,
+                Encoding::Deflate => ZlibDecoder::new(reader).read_to_end(&mut decoded).await,
+            }
+            .unwrap();
+            assert!(decoded == original, "{} 解压后的内容与原内容不同", encoding.name());
+        }
+    }
+
+    // 临时目录: root 下有 app.js 与预压缩文件，root 外有 secret.txt 的预压缩文件
+    struct StaticDir(PathBuf);
+
+    impl StaticDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("cool-nginx-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(dir.join("root/js")).unwrap();
+            std::fs::write(dir.join("root/js/app.js"), "console.log(1)").unwrap();
+            std::fs::write(dir.join("root/js/app.js.gz"), "gzip-bytes").unwrap();
+            std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+            std::fs::write(dir.join("secret.txt.gz"), "secret-gzip").unwrap();
+            std::fs::write(dir.join("secret.txt.br"), "secret-br").unwrap();
+            StaticDir(dir)
+        }
+
+        fn root(&self) -> PathBuf {
+            self.0.join("root")
+        }
+    }
+
+    impl Drop for StaticDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn static_request(method: Method, path: &str, accept_encoding: Option<&str>) -> Request<Body> {
+        let mut request = Request::builder().method(method).uri(path);
+        if let Some(accept_encoding) = accept_encoding {
+            request = request.header(header::ACCEPT_ENCODING, accept_encoding);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    async fn body_text(response: Response<Body>) -> String {
+        String::from_utf8(hyper::body::to_bytes(response.into_body()).await.unwrap().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn precompressed_cannot_escape_root() {
+        let dir = StaticDir::new("escape");
+        let root = dir.root();
+        for path in [
+            "/../secret.txt",
+            "/js/../../secret.txt",
+            "/%2e%2e/secret.txt",
+            "/%2E%2E%2fsecret.txt",
+            "/js/..%2F..%2Fsecret.txt",
+            "/js%2f%2e%2e%2f%2e%2e%2fsecret.txt",
+            "/..%5csecret.txt",
+            "/js/%00/../secret.txt",
+        ] {
+            let request = static_request(Method::GET, path, Some("br, gzip"));
+            assert!(serve_precompressed(&request, &root).await.is_none(), "{}", path);
+        }
+        // 根目录内的 .. 也不允许
+        let request = static_request(Method::GET, "/js/../js/app.js", Some("gzip"));
+        assert!(serve_precompressed(&request, &root).await.is_none());
+        // 编码后的普通路径仍然可以使用
+        let request = static_request(Method::GET, "/js/%61pp.js", Some("gzip"));
+        assert!(serve_precompressed(&request, &root).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn precompressed_only_when_accepted() {
+        let dir = StaticDir::new("accepted");
+        let root = dir.root();
+
+        for accept_encoding in [None, Some("br"), Some("identity"), Some("gzip;q=0"), Some("*;q=0")] {
+            let request = static_request(Method::GET, "/js/app.js", accept_encoding);
+            assert!(serve_precompressed(&request, &root).await.is_none(), "{:?}", accept_encoding);
+        }
+
+        let request = static_request(Method::GET, "/js/app.js", Some("br, gzip;q=0.5"));
+        let response = serve_precompressed(&request, &root).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(encoding_of(&response), Some("gzip"));
+        assert_eq!(vary(&response), vec!["Accept-Encoding"]);
+        // 与原文件的类型相同
+        let mime = mime_guess::from_path("app.js").first().unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], mime.as_ref());
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "10");
+        assert!(response.headers()[header::ETAG].to_str().unwrap().ends_with("-gzip\""));
+        assert_eq!(body_text(response).await, "gzip-bytes");
+
+        // 两种预压缩文件都存在时按 q 值选择
+        std::fs::write(root.join("js/app.js.br"), "br-bytes").unwrap();
+        let request = static_request(Method::GET, "/js/app.js", Some("gzip, br"));
+        let response = serve_precompressed(&request, &root).await.unwrap();
+        assert_eq!(encoding_of(&response), Some("br"));
+        assert_eq!(body_text(response).await, "br-bytes");
+        let request = static_request(Method::GET, "/js/app.js", Some("br;q=0.5, gzip"));
+        let response = serve_precompressed(&request, &root).await.unwrap();
+        assert_eq!(encoding_of(&response), Some("gzip"));
+
+        // HEAD 只返回头部，范围请求按原文件处理
+        let request = static_request(Method::HEAD, "/js/app.js", Some("br"));
+        let response = serve_precompressed(&request, &root).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "8");
+        assert_eq!(body_text(response).await, "");
+        let mut request = static_request(Method::GET, "/js/app.js", Some("br"));
+        request.headers_mut().insert(header::RANGE, HeaderValue::from_static("bytes=0-1"));
+        assert!(serve_precompressed(&request, &root).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn precompressed_not_modified() {
+        let dir = StaticDir::new("not-modified");
+        let root = dir.root();
+        let request = static_request(Method::GET, "/js/app.js", Some("gzip"));
+        let response = serve_precompressed(&request, &root).await.unwrap();
+        let etag = response.headers()[header::ETAG].clone();
+
+        let mut request = static_request(Method::GET, "/js/app.js", Some("gzip"));
+        request.headers_mut().insert(header::IF_NONE_MATCH, etag);
+        let response = serve_precompressed(&request, &root).await.unwrap();
+        assert_eq!(response.status(), 304);
+        assert_eq!(vary(&response), vec!["Accept-Encoding"]);
+    }
+}
@@ -0,0 +1,104 @@
+// Tauri 桌面应用
+//
+// 在窗口应用中运行服务器，提供配置管理界面。无界面运行时使用 cool-nginx serve。
+
+use crate::{cache, ServerConfig, CONFIG};
+
+#[cfg_attr(mobile, tauri::mobile_entry_point)]
+pub fn run() {
+    use tauri::Manager;
+
+    // 配置文件无效时不启动服务器，也不改用默认配置
+    if let Err(e) = crate::load_default_config() {
+        eprintln!("加载配置失败: {}", e);
+        std::process::exit(1);
+    }
+    
+    let result = tauri::Builder::default()
+        .setup(|app| {
+            // 获取主窗口
+            if let Some(window) = app.get_webview_window("main") {
+                // 增加延迟确保窗口完全初始化
+                std::thread::sleep(std::time::Duration::from_millis(200));
+                
+                // 设置窗口大小为固定的 1920x1080
+                let _ = window.set_size(tauri::PhysicalSize::new(1920, 1080));
+                let _ = window.center();
+                
+                println!("窗口已调整大小: 1920x1080");
+                
+                // 添加一个闭包，在稍后再次设置窗口大小以确保它不会被改变
+                let window_clone = window.clone();
+                std::thread::spawn(move || {
+                    std::thread::sleep(std::time::Duration::from_millis(500));
+                    let _ = window_clone.set_size(tauri::PhysicalSize::new(1920, 1080));
+                    println!("再次确认窗口大小: 1920x1080");
+                });
+            }
+            
+            // 在这里启动我们的Web服务器，应用退出时优雅关闭
+            app.manage(crate::start_server());
+
+            // 收到 SIGTERM/SIGINT 时退出应用
+            let app_handle = app.handle().clone();
+            std::thread::spawn(move || {
+                crate::wait_for_signal();
+                app_handle.exit(0);
+            });
+            
+            if cfg!(debug_assertions) {
+                if let Err(e) = app.handle().plugin(
+                    tauri_plugin_log::Builder::default()
+                        .level(log::LevelFilter::Info)
+                        .build(),
+                ) {
+                    eprintln!("Failed to initialize log plugin: {}", e);
+                }
+            }
+            Ok(())
+        })
+        .invoke_handler(tauri::generate_handler![get_config, crate::update_config, list_cache, purge_cache])
+        .build(tauri::generate_context!());
+        
+    match result {
+        Ok(app) => {
+            app.run(|app_handle, event| {
+                if let tauri::RunEvent::Exit = event {
+                    app_handle.state::<crate::ServerHandle>().shutdown();
+                    println!("Tauri application exited successfully");
+                }
+            });
+        },
+        Err(e) => {
+            eprintln!("Error while running tauri application: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// 获取当前配置
+#[tauri::command]
+fn get_config() -> Result<ServerConfig, String> {
+    // 直接从内存中获取配置，确保获取的是最新配置
+    let config = CONFIG.read().map_err(|e| format!("读取配置失败: {}", e))?;
+    println!("从内存加载配置成功: {:?}", config.server.listen_addr);
+    // 添加调试信息
+    println!("当前配置详情 - 静态文件根目录: {}, 静态文件服务: {}, 反向代理: {}", 
+             config.server.static_root, 
+             config.features.static_file_serving, 
+             config.features.reverse_proxy);
+    Ok(config.clone())
+}
+
+/// 列出反向代理缓存的条目
+#[tauri::command]
+fn list_cache() -> Result<Vec<cache::EntryInfo>, String> {
+    cache::entries()
+}
+
+/// 清除反向代理缓存，key、prefix、pattern 与 all 只能指定一个，返回清除的条目数
+#[tauri::command]
+async fn purge_cache(key: Option<String>, prefix: Option<String>, pattern: Option<String>, all: Option<bool>) -> Result<usize, String> {
+    let purge = cache::Purge::new(key, prefix, pattern, all.unwrap_or(false))?;
+    cache::purge(&purge).await
+}
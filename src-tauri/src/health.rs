@@ -0,0 +1,179 @@
+// 上游主动健康检查
+//
+// 后台任务按各上游服务器组 health_check 的配置定期向组内每个服务器发送探测请求，
+// 探测失败的服务器在恢复前不会被负载均衡选中。
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request};
+
+use crate::{balancer, proxy, upstream_groups, validated_duration, UpstreamSection, CONFIG};
+
+// 两次读取配置之间的最长间隔，保证配置变更能及时生效
+const MAX_TICK: Duration = Duration::from_secs(1);
+
+/// 健康检查任务，每轮重新读取配置，配置变更无需重启即可生效
+pub(crate) async fn run() {
+    let client = Client::new();
+    // 各服务器组下一次探测的时间
+    let mut next_check: HashMap<String, Instant> = HashMap::new();
+
+    loop {
+        let groups = upstream_groups(&CONFIG.read().unwrap());
+        balancer::sync_groups(&groups);
+        proxy::sync_clients(&groups);
+        next_check.retain(|name, _| groups.iter().any(|(group, _)| group == name));
+
+        check_groups(&client, &groups, &mut next_check).await;
+
+        // 休眠到最近一个服务器组需要探测的时间
+        let now = Instant::now();
+        let sleep = next_check
+            .values()
+            .map(|at| at.saturating_duration_since(now))
+            .min()
+            .unwrap_or(MAX_TICK)
+            .min(MAX_TICK);
+        tokio::time::sleep(sleep).await;
+    }
+}
+
+// 探测所有到了探测时间的服务器组，并记录探测结果
+async fn check_groups(
+    client: &Client<HttpConnector>,
+    groups: &[(String, UpstreamSection)],
+    next_check: &mut HashMap<String, Instant>,
+) {
+    let now = Instant::now();
+    let mut probes = Vec::new();
+
+    for (name, upstream) in groups {
+        let check = &upstream.health_check;
+        if !check.enabled || upstream.servers.is_empty() {
+            balancer::reset_health_checks(name);
+            next_check.remove(name);
+            continue;
+        }
+        if next_check.get(name).is_some_and(|at| *at > now) {
+            continue;
+        }
+
+        let interval = validated_duration(&check.interval, Duration::from_secs(5));
+        let timeout = validated_duration(&check.timeout, Duration::from_secs(2));
+        next_check.insert(name.clone(), now + interval);
+
+        // 并发探测组内所有服务器
+        for server in &upstream.servers {
+            let probe = probe(
+                client.clone(),
+                server.address.clone(),
+                check.path.clone(),
+                check.expected_status.clone(),
+                timeout,
+            );
+            probes.push((name.clone(), server.address.clone(), check.fails, check.passes, tokio::spawn(probe)));
+        }
+    }
+
+    for (group, address, fails, passes, probe) in probes {
+        let ok = probe.await.unwrap_or(false);
+        balancer::record_health_check(&group, &address, ok, fails, passes);
+    }
+}
+
+// 向上游服务器发送一次探测请求，返回是否健康
+async fn probe(
+    client: Client<HttpConnector>,
+    address: String,
+    path: String,
+    expected_status: Vec<u16>,
+    timeout: Duration,
+) -> bool {
+    let req = match Request::get(format!("http://{}{}", address, path))
+        .header(hyper::header::HOST, address.as_str())
+        .header(hyper::header::USER_AGENT, "rust-cool-nginx-health-check")
+        .body(Body::empty())
+    {
+        Ok(req) => req,
+        Err(e) => {
+            eprintln!("构造健康检查请求失败 {}: {}", address, e);
+            return false;
+        }
+    };
+
+    match tokio::time::timeout(timeout, client.request(req)).await {
+        Ok(Ok(response)) => expected_status.contains(&response.status().as_u16()),
+        Ok(Err(_)) | Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use hyper::Response;
+
+    // 启动一个本地上游，healthy 为 false 时健康检查路径返回 503
+    fn spawn_upstream(healthy: Arc<AtomicBool>) -> SocketAddr {
+        crate::test_util::spawn_server(move |_req| {
+            let status = if healthy.load(Ordering::SeqCst) { 200 } else { 503 };
+            async move { Response::builder().status(status).body(Body::empty()).unwrap() }
+        })
+    }
+
+    // 忽略探测间隔，立即探测一轮
+    async fn check_now(client: &Client<HttpConnector>, groups: &[(String, UpstreamSection)]) {
+        check_groups(client, groups, &mut HashMap::new()).await;
+    }
+
+    fn selected(group: &str, upstream: &UpstreamSection) -> Vec<String> {
+        (0..4)
+            .map(|_| {
+                balancer::select_upstream(group, upstream, true, [127, 0, 0, 1].into(), &[])
+                    .unwrap()
+                    .address
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn unhealthy_upstream_is_skipped_until_probe_passes() {
+        let flaky_healthy = Arc::new(AtomicBool::new(true));
+        let flaky = spawn_upstream(flaky_healthy.clone()).to_string();
+        let stable = spawn_upstream(Arc::new(AtomicBool::new(true))).to_string();
+
+        let upstream: UpstreamSection = serde_json::from_value(serde_json::json!({
+            "load_balancing_algorithm": "round_robin",
+            "servers": [
+                {"address": flaky, "weight": 1, "max_fails": 0, "fail_timeout": "10s"},
+                {"address": stable, "weight": 1, "max_fails": 0, "fail_timeout": "10s"}
+            ],
+            "health_check": {"enabled": true, "path": "/health", "timeout": "1s", "fails": 2, "passes": 2}
+        }))
+        .unwrap();
+        let group = "test_health_check".to_string();
+        let groups = vec![(group.clone(), upstream.clone())];
+        let client = Client::new();
+
+        assert!(selected(&group, &upstream).contains(&flaky));
+
+        // 连续失败 fails 次后才移出轮询
+        flaky_healthy.store(false, Ordering::SeqCst);
+        check_now(&client, &groups).await;
+        assert!(selected(&group, &upstream).contains(&flaky));
+        check_now(&client, &groups).await;
+        assert!(selected(&group, &upstream).iter().all(|address| *address == stable));
+
+        // 连续成功 passes 次后恢复
+        flaky_healthy.store(true, Ordering::SeqCst);
+        check_now(&client, &groups).await;
+        assert!(selected(&group, &upstream).iter().all(|address| *address == stable));
+        check_now(&client, &groups).await;
+        assert!(selected(&group, &upstream).contains(&flaky));
+    }
+}
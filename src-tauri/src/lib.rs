@@ -1,57 +1,23 @@
-#[cfg_attr(mobile, tauri::mobile_entry_point)]
-pub fn run() {
-    use tauri::Manager;
-    
-    let result = tauri::Builder::default()
-        .setup(|app| {
-            // 获取主窗口
-            if let Some(window) = app.get_webview_window("main") {
-                // 增加延迟确保窗口完全初始化
-                std::thread::sleep(std::time::Duration::from_millis(200));
-                
-                // 设置窗口大小为固定的 1920x1080
-                let _ = window.set_size(tauri::PhysicalSize::new(1920, 1080));
-                let _ = window.center();
-                
-                println!("窗口已调整大小: 1920x1080");
-                
-                // 添加一个闭包，在稍后再次设置窗口大小以确保它不会被改变
-                let window_clone = window.clone();
-                std::thread::spawn(move || {
-                    std::thread::sleep(std::time::Duration::from_millis(500));
-                    let _ = window_clone.set_size(tauri::PhysicalSize::new(1920, 1080));
-                    println!("再次确认窗口大小: 1920x1080");
-                });
-            }
-            
-            // 在这里启动我们的Web服务器
-            start_server();
-            
-            if cfg!(debug_assertions) {
-                if let Err(e) = app.handle().plugin(
-                    tauri_plugin_log::Builder::default()
-                        .level(log::LevelFilter::Info)
-                        .build(),
-                ) {
-                    eprintln!("Failed to initialize log plugin: {}", e);
-                }
-            }
-            Ok(())
-        })
-        .invoke_handler(tauri::generate_handler![get_config, update_config])
-        .run(tauri::generate_context!());
-        
-    match result {
-        Ok(_) => {
-            println!("Tauri application exited successfully");
-        },
-        Err(e) => {
-            eprintln!("Error while running tauri application: {}", e);
-            std::process::exit(1);
-        }
-    }
-}
+mod access_log;
+mod balancer;
+mod cache;
+mod compression;
+#[cfg(feature = "desktop")]
+mod desktop;
+mod health;
+mod listener;
+mod location;
+mod proxy;
+#[cfg(test)]
+mod test_util;
+mod tls;
+mod vhost;
+
+#[cfg(feature = "desktop")]
+pub use desktop::run;
 
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::fs;
@@ -64,122 +30,20 @@ struct MonitoringData {
     current_connections: u64,
     success_rate: f64,
     uptime: String,
+    upstreams: BTreeMap<String, Vec<balancer::PeerStatus>>,
 }
 
 // 全局配置
 lazy_static::lazy_static! {
-    static ref CONFIG: Arc<RwLock<ServerConfig>> = {
-        // 尝试从配置文件读取配置
-        let config_str = match fs::read_to_string("nginx.conf") {
-            Ok(content) => {
-                println!("成功从 nginx.conf 文件读取配置");
-                content
-            },
-            Err(e) => {
-                eprintln!("读取 nginx.conf 文件失败: {}，使用默认配置", e);
-                // 提供默认配置内容
-                r#"{
-                    "server": {
-                        "listen_addr": "127.0.0.1:8082",
-                        "backend_addr": "127.0.0.1:3000",
-                        "static_root": "./public",
-                        "access_log": "./logs/access.log",
-                        "error_log": "./logs/error.log",
-                        "log_level": "info",
-                        "ssl_cert_path": "",
-                        "ssl_key_path": "",
-                        "ssl_enabled": false
-                    },
-                    "upstream": {
-                        "load_balancing_algorithm": "round-robin",
-                        "servers": []
-                    },
-                    "features": {
-                        "static_file_serving": true,
-                        "reverse_proxy": true,
-                        "fastcgi_support": false,
-                        "load_balancing": true,
-                        "cache_enabled": false,
-                        "cache_path": "/tmp/nginx/cache",
-                        "cache_max_size": "100m",
-                        "cache_inactive": "10m",
-                        "gzip_compression": true,
-                        "gzip_comp_level": 6,
-                        "gzip_min_length": 1024,
-                        "gzip_types": ["text/plain", "text/css", "application/json", "application/javascript", "text/xml", "application/xml"],
-                        "virtual_hosts": false,
-                        "access_control": true,
-                        "allow_ips": ["127.0.0.1"],
-                        "deny_ips": [],
-                        "rate_limiting": true,
-                        "max_requests_per_minute": 1000,
-                        "ssl_tls": false,
-                        "websocket_support": true,
-                        "worker_processes": 1,
-                        "worker_connections": 1024,
-                        "monitoring_enabled": true,
-                        "stats_path": "/stats"
-                    }
-                }"#.to_string()
-            }
-        };
-        
-        let config: ServerConfig = match serde_json::from_str(&config_str) {
-            Ok(parsed_config) => {
-                println!("成功解析配置");
-                parsed_config
-            },
-            Err(e) => {
-                eprintln!("解析配置失败: {}，使用默认配置", e);
-                // 如果解析配置失败，则使用默认配置
-                serde_json::from_str(r#"{
-                    "server": {
-                        "listen_addr": "127.0.0.1:8082",
-                        "backend_addr": "127.0.0.1:3000",
-                        "static_root": "./public",
-                        "access_log": "./logs/access.log",
-                        "error_log": "./logs/error.log",
-                        "log_level": "info",
-                        "ssl_cert_path": "",
-                        "ssl_key_path": "",
-                        "ssl_enabled": false
-                    },
-                    "upstream": {
-                        "load_balancing_algorithm": "round-robin",
-                        "servers": []
-                    },
-                    "features": {
-                        "static_file_serving": true,
-                        "reverse_proxy": true,
-                        "fastcgi_support": false,
-                        "load_balancing": true,
-                        "cache_enabled": false,
-                        "cache_path": "/tmp/nginx/cache",
-                        "cache_max_size": "100m",
-                        "cache_inactive": "10m",
-                        "gzip_compression": true,
-                        "gzip_comp_level": 6,
-                        "gzip_min_length": 1024,
-                        "gzip_types": ["text/plain", "text/css", "application/json", "application/javascript", "text/xml", "application/xml"],
-                        "virtual_hosts": false,
-                        "access_control": true,
-                        "allow_ips": ["127.0.0.1"],
-                        "deny_ips": [],
-                        "rate_limiting": true,
-                        "max_requests_per_minute": 1000,
-                        "ssl_tls": false,
-                        "websocket_support": true,
-                        "worker_processes": 1,
-                        "worker_connections": 1024,
-                        "monitoring_enabled": true,
-                        "stats_path": "/stats"
-                    }
-                }"#).expect("默认配置无法解析")
-            }
-        };
-        Arc::new(RwLock::new(config))
-    };
+    // 配置文件路径，相对路径按当前目录解析
+    static ref CONFIG_PATH: RwLock<PathBuf> = RwLock::new(PathBuf::from("nginx.conf"));
+
+    // 启动时由 load_default_config 或 load_config 从配置文件加载，配置文件不存在时使用默认配置
+    static ref CONFIG: Arc<RwLock<ServerConfig>> = Arc::new(RwLock::new(default_config()));
     
+    // 配置版本号，每次 update_config 后加一，服务器据此重新绑定监听地址
+    static ref CONFIG_VERSION: tokio::sync::watch::Sender<u64> = tokio::sync::watch::Sender::new(0);
+
     // 监控数据
     static ref TOTAL_REQUESTS: AtomicU64 = AtomicU64::new(0);
     static ref CURRENT_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
@@ -197,14 +61,171 @@ fn get_monitoring_data() -> MonitoringData {
         current_connections: CURRENT_CONNECTIONS.load(Ordering::Relaxed),
         success_rate: 98.5, // 模拟成功率
         uptime: "2 days, 5:30:15".to_string(), // 模拟运行时间
+        upstreams: balancer::upstream_status(),
     }
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
 struct ServerConfig {
     server: ServerSection,
+    // 旧版的单个上游服务器组，作为名为 default 的组使用
+    #[serde(default)]
     upstream: UpstreamSection,
+    // 命名的上游服务器组，由 location 的 upstream 字段按名称引用
+    #[serde(default)]
+    upstreams: BTreeMap<String, UpstreamSection>,
     features: FeaturesSection,
+    #[serde(default = "default_locations")]
+    locations: Vec<LocationConfig>,
+    // 基于名称的虚拟主机，启用 features.virtual_hosts 时按 Host 头选择
+    #[serde(default)]
+    virtual_hosts: Vec<VirtualHostConfig>,
+}
+
+// 虚拟主机，未配置的日志路径使用 server 中的配置
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+struct VirtualHostConfig {
+    // 主机名: example.com、*.example.com、www.example.* 或以 ~ 开头的正则
+    server_names: Vec<String>,
+    // 没有虚拟主机匹配请求的主机名时使用
+    #[serde(default)]
+    default_server: bool,
+    // 静态文件根目录，为空时使用 server.static_root
+    #[serde(default)]
+    static_root: String,
+    #[serde(default)]
+    locations: Vec<LocationConfig>,
+    #[serde(default)]
+    access_log: String,
+    #[serde(default)]
+    error_log: String,
+    #[serde(default)]
+    limits: VirtualHostLimits,
+    // server_names 中以 ~ 开头的正则，随配置一起保存，每个配置版本只编译一次
+    #[serde(skip)]
+    server_name_regexes: location::CompiledRegexes,
+}
+
+// 虚拟主机的请求限制，0 表示不限制
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+#[serde(default)]
+struct VirtualHostLimits {
+    // 请求体的最大字节数，按 Content-Length 检查
+    client_max_body_size: u64,
+    max_requests_per_minute: u32,
+}
+
+// 请求路径匹配规则，决定请求由反向代理还是静态文件服务处理
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+struct LocationConfig {
+    // 匹配方式: prefix、exact 或 regex
+    #[serde(default = "default_match_type")]
+    match_type: String,
+    path: String,
+    // 处理方式: proxy 或 static
+    handler: String,
+    // 转发到的上游服务器组名称
+    #[serde(default)]
+    upstream: String,
+    // 直接转发到的地址 (host:port)，未配置 upstream 时使用，两者都为空时使用 server.backend_addr
+    #[serde(default)]
+    proxy_pass: String,
+    // 转发或查找文件前去掉匹配到的前缀
+    #[serde(default)]
+    strip_prefix: bool,
+    // 用于替换匹配部分的路径，正则匹配时可引用捕获组，如 "/api/$1"
+    #[serde(default)]
+    rewrite: String,
+    // 静态文件根目录，为空时使用 server.static_root
+    #[serde(default)]
+    root: String,
+    // 转发时附加的代理头部
+    #[serde(default)]
+    proxy_headers: ProxyHeadersConfig,
+    // 转发原始的 Host 头，而不是替换为上游服务器地址
+    #[serde(default)]
+    preserve_host: bool,
+    // 转发失败时改用组内下一个上游服务器的条件:
+    // error、timeout、http_500、http_502、http_503、http_504、http_403、http_404、http_429、non_idempotent 或 off
+    #[serde(default = "default_proxy_next_upstream")]
+    proxy_next_upstream: Vec<String>,
+    // 最多尝试的上游服务器数，0 表示不限制
+    #[serde(default)]
+    proxy_next_upstream_tries: u32,
+    // 为重试而缓存的请求体上限 (字节)，更大的请求体直接流式转发且不会重试
+    #[serde(default = "default_proxy_next_upstream_body_size")]
+    proxy_next_upstream_body_size: usize,
+    // 访问该 location 对客户端证书的要求
+    #[serde(default)]
+    client_cert: ClientCertAccess,
+    // match_type 为 regex 时编译好的 path，随配置一起保存，每个配置版本只编译一次
+    #[serde(skip)]
+    regex: location::CompiledRegexes,
+}
+
+// 转发请求时附加的标准代理头部
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+#[serde(default)]
+struct ProxyHeadersConfig {
+    x_forwarded_for: bool,
+    x_forwarded_proto: bool,
+    x_forwarded_host: bool,
+    x_real_ip: bool,
+    // RFC 7239 Forwarded 头
+    forwarded: bool,
+    via: bool,
+    // 经过校验的客户端证书主题
+    x_client_cert_subject: bool,
+}
+
+impl Default for ProxyHeadersConfig {
+    fn default() -> Self {
+        ProxyHeadersConfig {
+            x_forwarded_for: true,
+            x_forwarded_proto: true,
+            x_forwarded_host: true,
+            x_real_ip: true,
+            forwarded: false,
+            via: false,
+            x_client_cert_subject: true,
+        }
+    }
+}
+
+fn default_match_type() -> String {
+    "prefix".to_string()
+}
+
+fn default_proxy_next_upstream() -> Vec<String> {
+    ["error", "timeout", "http_502", "http_503", "http_504"]
+        .iter()
+        .map(|condition| condition.to_string())
+        .collect()
+}
+
+fn default_proxy_next_upstream_body_size() -> usize {
+    64 * 1024
+}
+
+// 未配置 locations 时保持原有行为: /admin-api/ 转发到上游服务器，其余请求由静态文件服务处理
+fn default_locations() -> Vec<LocationConfig> {
+    vec![LocationConfig {
+        match_type: default_match_type(),
+        path: "/admin-api/".to_string(),
+        handler: "proxy".to_string(),
+        upstream: "default".to_string(),
+        proxy_pass: String::new(),
+        strip_prefix: false,
+        rewrite: String::new(),
+        root: String::new(),
+        proxy_headers: ProxyHeadersConfig::default(),
+        preserve_host: false,
+        proxy_next_upstream: default_proxy_next_upstream(),
+        proxy_next_upstream_tries: 0,
+        proxy_next_upstream_body_size: default_proxy_next_upstream_body_size(),
+        client_cert: ClientCertAccess::default(),
+        regex: location::CompiledRegexes::default(),
+    }]
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
@@ -218,12 +239,203 @@ struct ServerSection {
     ssl_cert_path: String,
     ssl_key_path: String,
     ssl_enabled: bool,
+    // 按 TLS SNI 选择的各虚拟主机证书，启用 features.virtual_hosts 时生效，
+    // 未匹配任何主机名时使用 ssl_cert_path 与 ssl_key_path 配置的默认证书
+    #[serde(default)]
+    ssl_certificates: Vec<SslCertificate>,
+    // 客户端证书校验: off、optional 或 required
+    #[serde(default = "default_ssl_verify_client")]
+    ssl_verify_client: String,
+    // 用于校验客户端证书的 CA 证书 (PEM)
+    #[serde(default)]
+    ssl_client_ca_path: String,
+    // 访问 /api/config 与 /api/cache 对客户端证书的要求
+    #[serde(default)]
+    config_api_client_cert: ClientCertAccess,
+    // HTTPS 监听地址，为空时 listen_addr 只提供 HTTPS；配置了 listen 时不使用
+    #[serde(default)]
+    ssl_listen_addr: String,
+    // 多个监听地址，配置后代替 listen_addr 与 ssl_listen_addr
+    #[serde(default)]
+    listen: Vec<ListenConfig>,
+    // listen_addr 上的 HTTP 请求重定向到 HTTPS
+    #[serde(default)]
+    http_redirect: bool,
+    // 重定向使用的状态码: 301 或 308
+    #[serde(default = "default_http_redirect_status")]
+    http_redirect_status: u16,
+    // HTTPS 响应附加的 Strict-Transport-Security 头
+    #[serde(default)]
+    hsts: HstsConfig,
+    // 关闭服务器时等待进行中的请求完成的最长时间
+    #[serde(default = "default_shutdown_timeout")]
+    shutdown_timeout: String,
+}
+
+// 监听地址，IPv6 地址如 "[::]:8080"
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+struct ListenConfig {
+    addr: String,
+    // 使用 HTTPS，需要开启 server.ssl_enabled
+    #[serde(default)]
+    ssl: bool,
+    // 同时接受 HTTP/2 连接
+    #[serde(default)]
+    http2: bool,
+    // IPv6 地址只接受 IPv6 连接，关闭时同时接受 IPv4 连接 (双栈)
+    #[serde(default = "default_ipv6_only")]
+    ipv6_only: bool,
+}
+
+fn default_ipv6_only() -> bool {
+    true
+}
+
+fn default_http_redirect_status() -> u16 {
+    301
+}
+
+fn default_shutdown_timeout() -> String {
+    "30s".to_string()
+}
+
+fn default_cache_vary() -> Vec<String> {
+    vec!["Accept-Encoding".to_string()]
+}
+
+fn default_cache_lock_timeout() -> String {
+    "5s".to_string()
+}
+
+fn default_cache_use_stale() -> Vec<String> {
+    ["error", "timeout", "updating", "http_502", "http_503", "http_504"]
+        .iter()
+        .map(|condition| condition.to_string())
+        .collect()
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+#[serde(default)]
+struct HstsConfig {
+    enabled: bool,
+    max_age: u64,
+    include_subdomains: bool,
+    preload: bool,
+}
+
+impl Default for HstsConfig {
+    fn default() -> Self {
+        HstsConfig {
+            enabled: false,
+            // 一年
+            max_age: 31536000,
+            include_subdomains: false,
+            preload: false,
+        }
+    }
+}
+
+fn default_ssl_verify_client() -> String {
+    "off".to_string()
+}
+
+// 按客户端证书限制访问
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+#[serde(default)]
+struct ClientCertAccess {
+    // 必须提供经过校验的客户端证书
+    required: bool,
+    // 允许访问的证书主题，每项为一个或多个属性 (如 "CN=admin" 或 "CN=admin, O=Example")，
+    // 证书主题包含其中全部属性时允许访问；值中的逗号写作 "\,"。为空时不限制
+    allowed_subjects: Vec<String>,
+}
+
+// 虚拟主机证书
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+struct SslCertificate {
+    // 使用该证书的主机名，支持 "*.example.com" 形式的通配符
+    server_names: Vec<String>,
+    cert_path: String,
+    key_path: String,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
 struct UpstreamSection {
     load_balancing_algorithm: String,
     servers: Vec<UpstreamServer>,
+    #[serde(default)]
+    health_check: HealthCheckConfig,
+    // 与上游服务器建立连接的超时时间
+    #[serde(default = "default_proxy_timeout")]
+    proxy_connect_timeout: String,
+    // 等待上游响应以及两次读取响应之间的超时时间
+    #[serde(default = "default_proxy_timeout")]
+    proxy_read_timeout: String,
+    // 向上游发送请求时两次写入之间的超时时间
+    #[serde(default = "default_proxy_timeout")]
+    proxy_send_timeout: String,
+    // 每个上游服务器保持的最大空闲连接数
+    #[serde(default = "default_keepalive")]
+    keepalive: usize,
+    // 空闲连接的保持时间
+    #[serde(default = "default_proxy_timeout")]
+    keepalive_timeout: String,
+}
+
+fn default_proxy_timeout() -> String {
+    "60s".to_string()
+}
+
+fn default_keepalive() -> usize {
+    32
+}
+
+impl Default for UpstreamSection {
+    fn default() -> Self {
+        UpstreamSection {
+            load_balancing_algorithm: "round_robin".to_string(),
+            servers: Vec::new(),
+            health_check: HealthCheckConfig::default(),
+            proxy_connect_timeout: default_proxy_timeout(),
+            proxy_read_timeout: default_proxy_timeout(),
+            proxy_send_timeout: default_proxy_timeout(),
+            keepalive: default_keepalive(),
+            keepalive_timeout: default_proxy_timeout(),
+        }
+    }
+}
+
+// 上游主动健康检查配置
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+#[serde(default)]
+struct HealthCheckConfig {
+    enabled: bool,
+    // 探测请求的路径
+    path: String,
+    // 两次探测之间的间隔
+    interval: String,
+    // 单次探测的超时时间
+    timeout: String,
+    // 视为健康的响应状态码
+    expected_status: Vec<u16>,
+    // 连续失败多少次后移出轮询
+    fails: u32,
+    // 连续成功多少次后恢复
+    passes: u32,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        HealthCheckConfig {
+            enabled: false,
+            path: "/".to_string(),
+            interval: "5s".to_string(),
+            timeout: "2s".to_string(),
+            expected_status: vec![200],
+            fails: 1,
+            passes: 1,
+        }
+    }
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
@@ -244,17 +456,28 @@ struct FeaturesSection {
     cache_path: String,
     cache_max_size: String,
     cache_inactive: String,
+    // 附加到缓存键中的请求头部，响应的 Vary 只能包含这些头部
+    #[serde(default = "default_cache_vary")]
+    cache_vary: Vec<String>,
+    // 上游失败或条目正在更新时返回过期的缓存条目
+    #[serde(default = "default_cache_use_stale")]
+    cache_use_stale: Vec<String>,
+    // 等待其他请求获取同一个缓存键的最长时间，超时后自己访问上游
+    #[serde(default = "default_cache_lock_timeout")]
+    cache_lock_timeout: String,
     gzip_compression: bool,
     gzip_comp_level: u32,
     gzip_min_length: u32,
     gzip_types: Vec<String>,
+    // 客户端接受时使用静态文件旁预压缩的 .br / .gz 文件
+    #[serde(default)]
+    gzip_static: bool,
     virtual_hosts: bool,
     access_control: bool,
     allow_ips: Vec<String>,
     deny_ips: Vec<String>,
     rate_limiting: bool,
     max_requests_per_minute: u32,
-    ssl_tls: bool,
     websocket_support: bool,
     worker_processes: u32,
     worker_connections: u32,
@@ -262,30 +485,216 @@ struct FeaturesSection {
     stats_path: String,
 }
 
-/// 获取当前配置
-#[tauri::command]
-fn get_config() -> Result<ServerConfig, String> {
-    // 直接从内存中获取配置，确保获取的是最新配置
-    let config = CONFIG.read().map_err(|e| format!("读取配置失败: {}", e))?;
-    println!("从内存加载配置成功: {:?}", config.server.listen_addr);
-    // 添加调试信息
-    println!("当前配置详情 - 静态文件根目录: {}, 静态文件服务: {}, 反向代理: {}", 
-             config.server.static_root, 
-             config.features.static_file_serving, 
-             config.features.reverse_proxy);
-    Ok(config.clone())
+// 未在 upstreams 中定义 default 组时，旧版的 upstream 配置作为 default 组
+const DEFAULT_UPSTREAM_GROUP: &str = "default";
+
+// 按名称查找上游服务器组
+fn find_upstream_group<'a>(config: &'a ServerConfig, name: &str) -> Option<&'a UpstreamSection> {
+    match config.upstreams.get(name) {
+        Some(upstream) => Some(upstream),
+        None if name == DEFAULT_UPSTREAM_GROUP => Some(&config.upstream),
+        None => None,
+    }
+}
+
+// 所有上游服务器组: (组名, 配置)
+fn upstream_groups(config: &ServerConfig) -> Vec<(String, UpstreamSection)> {
+    let mut groups: Vec<(String, UpstreamSection)> = config
+        .upstreams
+        .iter()
+        .map(|(name, upstream)| (name.clone(), upstream.clone()))
+        .collect();
+    if !config.upstreams.contains_key(DEFAULT_UPSTREAM_GROUP) {
+        groups.push((DEFAULT_UPSTREAM_GROUP.to_string(), config.upstream.clone()));
+    }
+    groups
+}
+
+// 校验配置中无法由 serde 检查的取值
+fn validate_config(config: &ServerConfig) -> Result<(), String> {
+    if config.upstreams.contains_key(DEFAULT_UPSTREAM_GROUP) && !config.upstream.servers.is_empty() {
+        return Err("upstream 与 upstreams.default 不能同时配置服务器".to_string());
+    }
+    for (name, upstream) in upstream_groups(config) {
+        validate_upstream_group(&upstream).map_err(|e| format!("上游服务器组 {}: {}", name, e))?;
+    }
+    listener::validate(&config.server)?;
+    compression::validate(&config.features)?;
+    cache::validate(&config.features)?;
+    parse_duration(&config.server.shutdown_timeout).map_err(|e| format!("shutdown_timeout 无效: {}", e))?;
+    location::validate(config)?;
+    vhost::validate(config)?;
+    tls::validate(config)?;
+    Ok(())
+}
+
+fn validate_upstream_group(upstream: &UpstreamSection) -> Result<(), String> {
+    balancer::Algorithm::parse(&upstream.load_balancing_algorithm)?;
+    for server in &upstream.servers {
+        if server.weight > balancer::MAX_WEIGHT {
+            return Err(format!(
+                "上游服务器 {} 的 weight 不能大于 {}",
+                server.address,
+                balancer::MAX_WEIGHT
+            ));
+        }
+        parse_duration(&server.fail_timeout)
+            .map_err(|e| format!("上游服务器 {} 的 fail_timeout 无效: {}", server.address, e))?;
+    }
+    let health_check = &upstream.health_check;
+    if !health_check.path.starts_with('/') {
+        return Err(format!("健康检查路径必须以 / 开头: {}", health_check.path));
+    }
+    if parse_duration(&health_check.interval)?.is_zero() {
+        return Err("健康检查间隔不能为 0".to_string());
+    }
+    parse_duration(&health_check.timeout)?;
+    for (name, value) in [
+        ("proxy_connect_timeout", &upstream.proxy_connect_timeout),
+        ("proxy_read_timeout", &upstream.proxy_read_timeout),
+        ("proxy_send_timeout", &upstream.proxy_send_timeout),
+        ("keepalive_timeout", &upstream.keepalive_timeout),
+    ] {
+        if parse_duration(value).map_err(|e| format!("{} 无效: {}", name, e))?.is_zero() {
+            return Err(format!("{} 不能为 0", name));
+        }
+    }
+    Ok(())
+}
+
+// 解析 nginx 风格的时间，如 "500ms"、"10s"、"60m"、"1h"、"1d"，不带单位时按秒处理
+fn parse_duration(value: &str) -> Result<std::time::Duration, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().map_err(|_| format!("无法解析时间: {:?}", value))?;
+    let seconds = match unit {
+        "ms" => return Ok(std::time::Duration::from_millis(number)),
+        "" | "s" => number,
+        "m" => number * 60,
+        "h" => number * 60 * 60,
+        "d" => number * 60 * 60 * 24,
+        _ => return Err(format!("无法解析时间单位: {:?}", value)),
+    };
+    Ok(std::time::Duration::from_secs(seconds))
+}
+
+// 读取已经过 validate_config 校验的时间配置。
+// 配置在加载和更新时都会校验，这里只有未经校验的配置 (如测试中直接构造的配置) 才会使用 default
+fn validated_duration(value: &str, default: std::time::Duration) -> std::time::Duration {
+    parse_duration(value).unwrap_or(default)
+}
+
+// 内置的默认配置，配置文件不存在时使用
+fn default_config() -> ServerConfig {
+    serde_json::from_str(r#"{
+        "server": {
+            "listen_addr": "127.0.0.1:8082",
+            "backend_addr": "127.0.0.1:3000",
+            "static_root": "./public",
+            "access_log": "./logs/access.log",
+            "error_log": "./logs/error.log",
+            "log_level": "info",
+            "ssl_cert_path": "",
+            "ssl_key_path": "",
+            "ssl_enabled": false
+        },
+        "upstream": {
+            "load_balancing_algorithm": "round_robin",
+            "servers": []
+        },
+        "features": {
+            "static_file_serving": true,
+            "reverse_proxy": true,
+            "fastcgi_support": false,
+            "load_balancing": true,
+            "cache_enabled": false,
+            "cache_path": "/tmp/nginx/cache",
+            "cache_max_size": "100m",
+            "cache_inactive": "10m",
+            "gzip_compression": true,
+            "gzip_comp_level": 6,
+            "gzip_min_length": 1024,
+            "gzip_types": ["text/plain", "text/css", "application/json", "application/javascript", "text/xml", "application/xml"],
+            "virtual_hosts": false,
+            "access_control": true,
+            "allow_ips": ["127.0.0.1"],
+            "deny_ips": [],
+            "rate_limiting": true,
+            "max_requests_per_minute": 1000,
+            "websocket_support": true,
+            "worker_processes": 1,
+            "worker_connections": 1024,
+            "monitoring_enabled": true,
+            "stats_path": "/stats"
+        }
+    }"#).expect("默认配置无法解析")
+}
+
+/// 使用指定的配置文件，需要在启动服务器之前调用。配置文件无法读取或无效时返回错误
+pub fn load_config(path: &Path) -> Result<(), String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("读取配置文件失败: {}，路径: {:?}", e, path))?;
+    let new_config: ServerConfig =
+        serde_json::from_str(&content).map_err(|e| format!("解析配置文件失败: {}，路径: {:?}", e, path))?;
+    validate_config(&new_config)?;
+
+    *CONFIG_PATH.write().map_err(|e| format!("获取写入锁失败: {}", e))? = path.to_path_buf();
+    let mut config = CONFIG.write().map_err(|e| format!("获取写入锁失败: {}", e))?;
+    *config = new_config;
+    println!("成功从 {:?} 文件读取配置", path);
+    Ok(())
+}
+
+/// 加载当前目录下的默认配置文件 nginx.conf，需要在启动服务器之前调用。
+/// 文件不存在时使用默认配置，首次保存配置时创建；文件无法解析或配置无效时返回错误，不启动服务器
+pub fn load_default_config() -> Result<(), String> {
+    let path = CONFIG_PATH.read().map_err(|e| format!("读取配置路径失败: {}", e))?.clone();
+    if !path.exists() {
+        println!("配置文件 {:?} 不存在，使用默认配置", path);
+        return Ok(());
+    }
+    load_config(&path)
 }
 
 /// 更新配置
+#[cfg(feature = "desktop")]
 #[tauri::command]
 fn update_config(new_config: ServerConfig) -> Result<(), String> {
+    apply_config(new_config).map_err(|e| match e {
+        UpdateConfigError::Invalid(e) => format!("配置无效: {}", e),
+        UpdateConfigError::Failed(e) => e,
+    })
+}
+
+// 更新配置失败的原因：配置本身无效，或应用、保存配置时出错
+enum UpdateConfigError {
+    Invalid(String),
+    Failed(String),
+}
+
+// 校验并应用新配置，然后保存到配置文件
+fn apply_config(new_config: ServerConfig) -> Result<(), UpdateConfigError> {
     println!("开始更新配置: {:?}", new_config);
     
+    validate_config(&new_config).map_err(UpdateConfigError::Invalid)?;
+    save_config(new_config).map_err(UpdateConfigError::Failed)
+}
+
+// 应用已校验的配置并保存到配置文件
+fn save_config(new_config: ServerConfig) -> Result<(), String> {
+    // 先加载新的证书，无效时拒绝整个更新并继续使用原证书
+    if new_config.server.ssl_enabled {
+        tls::reload(&new_config.server, new_config.features.virtual_hosts)
+            .map_err(|e| format!("证书无效，继续使用原证书: {}", e))?;
+    }
+    
     // 更新内存中的配置
     {
         let mut config = CONFIG.write().map_err(|e| format!("获取写入锁失败: {}", e))?;
         *config = new_config.clone();
     }
+    // 通知服务器应用新的监听地址与静态文件目录
+    CONFIG_VERSION.send_modify(|version| *version += 1);
     
     // 持久化配置到文件
     let config_str = serde_json::to_string_pretty(&new_config)
@@ -293,254 +702,469 @@ fn update_config(new_config: ServerConfig) -> Result<(), String> {
     
     // 使用完整路径写入配置文件
     let current_dir = std::env::current_dir().map_err(|e| format!("获取当前目录失败: {}", e))?;
-    let config_path = current_dir.join("nginx.conf");
+    let config_path = current_dir.join(&*CONFIG_PATH.read().map_err(|e| format!("读取配置路径失败: {}", e))?);
     
     println!("准备写入配置文件，路径: {:?}", config_path);
     
     fs::write(&config_path, config_str)
         .map_err(|e| format!("写入配置文件失败: {}，路径: {:?}", e, config_path))?;
     
-    println!("配置已更新并保存到 nginx.conf 文件，路径: {:?}", config_path);
+    println!("配置已更新并保存到配置文件，路径: {:?}", config_path);
     Ok(())
 }
 
-fn start_server() {
-    // 创建一个永不结束的后台线程来运行服务器
-    std::thread::spawn(|| {
+// 连接信息，HTTP 与 HTTPS 连接共用同一个请求处理函数
+#[derive(Clone, Debug)]
+struct ConnectionInfo {
+    remote_addr: std::net::SocketAddr,
+    // 是否为 HTTPS 连接
+    secure: bool,
+    // 经过校验的客户端证书主题
+    client_cert_subject: Option<tls::CertSubject>,
+    // TLS 握手时客户端通过 SNI 发送的主机名
+    server_name: Option<String>,
+}
+
+// 缺少客户端证书或证书主题不允许访问
+fn client_cert_forbidden() -> hyper::Response<hyper::Body> {
+    hyper::Response::builder()
+        .status(403)
+        .header("Access-Control-Allow-Origin", "*")
+        .body(hyper::Body::from("Forbidden: valid client certificate required"))
+        .unwrap()
+}
+
+// 配置更新端点的错误响应
+fn config_error(status: u16, error_msg: String) -> hyper::Response<hyper::Body> {
+    eprintln!("{}", error_msg);
+    hyper::Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(hyper::Body::from(serde_json::json!({"error": error_msg}).to_string()))
+        .unwrap()
+}
+
+// 处理单个请求：选择虚拟主机并检查其限制，交给 route_request 处理后写入访问日志
+async fn handle_request(
+    req: hyper::Request<hyper::Body>,
+    conn: ConnectionInfo,
+    static_root: String,
+    stats_path: String,
+) -> Result<hyper::Response<hyper::Body>, std::convert::Infallible> {
+    // 增加请求数量
+    increment_requests();
+    let started = std::time::Instant::now();
+
+    // 按主机名选择虚拟主机，并在其 locations 中查找匹配的 location
+    let (site, location, compression) = {
+        let config = CONFIG.read().unwrap();
+        let host = vhost::request_host(&req, &conn);
+        let vhost = vhost::find(&config, host.as_deref());
+        let locations = vhost.map_or(&config.locations, |vhost| &vhost.locations);
+        (
+            vhost::Site::new(&config, vhost),
+            location::find(locations, req.uri().path()).cloned(),
+            compression::Settings::new(&config.features),
+        )
+    };
+    let static_root = site.static_root.clone().unwrap_or(static_root);
+    let request = access_log::RequestInfo::new(&req, &conn);
+    let accept_encoding = req
+        .headers()
+        .get(hyper::header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+
+    let mut response = match site.check_limits(&req) {
+        Some(response) => response,
+        None => route_request(req, conn, location, static_root, stats_path).await?,
+    };
+    if let Some(compression) = &compression {
+        response = compression::compress(response, accept_encoding.as_deref(), compression);
+    }
+    access_log::log(&site, &request, &response, started.elapsed());
+    Ok(response)
+}
+
+// 按端点与 location 分发请求
+async fn route_request(
+    req: hyper::Request<hyper::Body>,
+    conn: ConnectionInfo,
+    location: Option<LocationConfig>,
+    static_root: String,
+    stats_path: String,
+) -> Result<hyper::Response<hyper::Body>, std::convert::Infallible> {
+    use hyper::{Body, Response};
+    use hyper_staticfile::Static;
+    use std::convert::Infallible;
+    use std::path::Path;
+
+    // 启用 HTTPS 重定向时，HTTP 请求全部重定向到 HTTPS
+    if !conn.secure {
+        let redirect = tls::https_redirect(&CONFIG.read().unwrap().server, &req);
+        if let Some(response) = redirect {
+            return Ok::<_, Infallible>(response);
+        }
+    }
+
+    // 处理 CORS 预检请求
+    if req.method() == hyper::Method::OPTIONS {
+        let response = Response::builder()
+            .status(200)
+            .header("Access-Control-Allow-Origin", "*")
+            .header("Access-Control-Allow-Methods", "GET, POST, PUT, DELETE, OPTIONS")
+            .header("Access-Control-Allow-Headers", "Content-Type, Authorization")
+            .header("Access-Control-Max-Age", "86400")
+            .body(Body::from(""))
+            .unwrap();
+        return Ok::<_, Infallible>(response);
+    }
+
+    // 检查是否是监控端点
+    if req.uri().path() == stats_path {
+        let monitoring_data = get_monitoring_data();
+        let json_data = serde_json::to_string_pretty(&monitoring_data).unwrap();
+        let response = Response::builder()
+            .header("Content-Type", "application/json")
+            .header("Access-Control-Allow-Origin", "*")
+            .header("Access-Control-Allow-Methods", "GET, OPTIONS")
+            .header("Access-Control-Allow-Headers", "Content-Type")
+            .body(Body::from(json_data))
+            .unwrap();
+        return Ok::<_, Infallible>(response);
+    }
+
+    // 配置管理端点可以要求客户端证书
+    if req.uri().path() == "/api/config" {
+        let access = CONFIG.read().unwrap().server.config_api_client_cert.clone();
+        if !tls::client_cert_allowed(&access, &conn) {
+            return Ok::<_, Infallible>(client_cert_forbidden());
+        }
+    }
+
+    // 检查是否是配置获取端点
+    if req.uri().path() == "/api/config" && req.method() == hyper::Method::GET {
+        // 从全局配置中获取配置信息
+        let config = CONFIG.read().unwrap();
+        let json_data = serde_json::to_string_pretty(&*config).unwrap();
+        let response = Response::builder()
+            .header("Content-Type", "application/json")
+            .header("Access-Control-Allow-Origin", "*")
+            .header("Access-Control-Allow-Methods", "GET, OPTIONS")
+            .header("Access-Control-Allow-Headers", "Content-Type")
+            .body(Body::from(json_data))
+            .unwrap();
+        return Ok::<_, Infallible>(response);
+    }
+
+    // 检查是否是配置更新端点
+    if req.uri().path() == "/api/config" && req.method() == hyper::Method::PUT {
+        // 读取并解析请求体，读取失败或不是有效的 JSON 都属于请求错误
+        let body_bytes = match hyper::body::to_bytes(req.into_body()).await {
+            Ok(bytes) => bytes,
+            Err(e) => return Ok::<_, Infallible>(config_error(400, format!("读取请求体失败: {}", e))),
+        };
+        let new_config: ServerConfig = match serde_json::from_slice(&body_bytes) {
+            Ok(config) => config,
+            Err(e) => return Ok::<_, Infallible>(config_error(400, format!("解析配置失败: {}", e))),
+        };
+
+        // 更新配置，配置无效属于请求错误，返回 400
+        match apply_config(new_config) {
+            Ok(()) => {
+                let response = Response::builder()
+                    .status(200)
+                    .header("Content-Type", "application/json")
+                    .header("Access-Control-Allow-Origin", "*")
+                    .body(Body::from(serde_json::json!({"message": "配置更新成功"}).to_string()))
+                    .unwrap();
+                return Ok::<_, Infallible>(response);
+            },
+            Err(UpdateConfigError::Invalid(e)) => {
+                return Ok::<_, Infallible>(config_error(400, format!("配置无效: {}", e)));
+            }
+            Err(UpdateConfigError::Failed(e)) => {
+                return Ok::<_, Infallible>(config_error(500, format!("更新配置失败: {}", e)));
+            }
+        }
+    }
+
+    // 缓存管理端点，未配置客户端证书限制时只允许本机访问
+    if req.uri().path() == "/api/cache" {
+        let access = CONFIG.read().unwrap().server.config_api_client_cert.clone();
+        if !cache::api_allowed(&access, &conn) {
+            return Ok::<_, Infallible>(client_cert_forbidden());
+        }
+        return Ok::<_, Infallible>(cache::api(&req).await);
+    }
+
+    // 按匹配到的 location 决定请求的处理方式
+    let mut req = req;
+    let static_root = match &location {
+        Some(location) => {
+            if !tls::client_cert_allowed(&location.client_cert, &conn) {
+                return Ok::<_, Infallible>(client_cert_forbidden());
+            }
+            // 缓存键使用改写前的 URI
+            let cache = if location.handler == "proxy" { cache::request(&req, &conn) } else { None };
+            location::rewrite_request(location, &mut req);
+            if location.handler == "proxy" {
+                return Ok::<_, Infallible>(cache::forward(req, cache, &conn, location).await);
+            }
+            if location.root.is_empty() {
+                static_root
+            } else {
+                location.root.clone()
+            }
+        }
+        None => static_root,
+    };
+
+    // 启用 gzip_static 时优先使用预压缩的 .br / .gz 文件
+    let gzip_static = CONFIG.read().unwrap().features.gzip_static;
+    let precompressed = if gzip_static {
+        compression::serve_precompressed(&req, Path::new(&static_root)).await
+    } else {
+        None
+    };
+
+    // 提供静态文件服务
+    let served = match precompressed {
+        Some(response) => Ok(response),
+        None => Static::new(Path::new(&static_root)).serve(req).await.map(|mut response| {
+            // 同一路径对其他客户端可能返回预压缩文件
+            if gzip_static {
+                compression::add_vary(response.headers_mut());
+            }
+            response
+        }),
+    };
+    match served {
+        Ok(mut response) => {
+            // 为静态文件响应也添加 CORS 头
+            response.headers_mut().insert(
+                "Access-Control-Allow-Origin",
+                hyper::header::HeaderValue::from_static("*")
+            );
+            response.headers_mut().insert(
+                "Access-Control-Allow-Methods",
+                hyper::header::HeaderValue::from_static("GET, OPTIONS")
+            );
+            response.headers_mut().insert(
+                "Access-Control-Allow-Headers",
+                hyper::header::HeaderValue::from_static("Content-Type")
+            );
+            Ok::<_, Infallible>(response)
+        },
+        Err(e) => {
+            eprintln!("Static file serving error: {}", e);
+            let response = Response::builder()
+                .status(500)
+                .header("Access-Control-Allow-Origin", "*")
+                .body(Body::from("Internal Server Error"))
+                .unwrap();
+            Ok(response)
+        }
+    }
+}
+
+/// 服务器线程的句柄
+pub struct ServerHandle {
+    shutdown: tokio::sync::watch::Sender<bool>,
+    thread: std::sync::Mutex<Option<std::thread::JoinHandle<()>>>,
+}
+
+impl ServerHandle {
+    /// 停止接受新连接，等待进行中的请求在 shutdown_timeout 内完成并写入访问日志后返回
+    pub fn shutdown(&self) {
+        let _ = self.shutdown.send(true);
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// 阻塞当前线程直到收到 SIGTERM 或 SIGINT
+pub fn wait_for_signal() {
+    let rt = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+        Ok(rt) => rt,
+        Err(e) => {
+            eprintln!("创建信号处理运行时失败: {}", e);
+            return;
+        }
+    };
+    rt.block_on(async {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            match signal(SignalKind::terminate()) {
+                Ok(mut terminate) => {
+                    tokio::select! {
+                        _ = terminate.recv() => println!("收到 SIGTERM"),
+                        _ = tokio::signal::ctrl_c() => println!("收到 SIGINT"),
+                    }
+                    return;
+                }
+                Err(e) => eprintln!("监听 SIGTERM 失败: {}", e),
+            }
+        }
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            eprintln!("监听 SIGINT 失败: {}", e);
+            // 无法监听信号时不退出
+            std::future::pending::<()>().await;
+        }
+        println!("收到 SIGINT");
+    });
+}
+
+/// 在后台线程中启动服务器，配置更新后自动应用新的监听地址
+pub fn start_server() -> ServerHandle {
+    let (shutdown, mut shutdown_requested) = tokio::sync::watch::channel(false);
+    // 创建运行服务器的后台线程，调用 ServerHandle::shutdown 后结束
+    let thread = std::thread::spawn(move || {
         // 使用 tokio 运行时来处理异步服务器
         let rt = tokio::runtime::Runtime::new().expect("Failed to create Tokio runtime");
         rt.block_on(async {
-            use hyper::service::{make_service_fn, service_fn};
-            use hyper::{Body, Request, Response, Server};
-            use hyper_staticfile::Static;
-            use std::convert::Infallible;
-            use std::net::SocketAddr;
-            use std::path::Path;
+            // 后台运行上游主动健康检查
+            tokio::spawn(health::run());
+            // 证书文件变化时自动重新加载
+            tokio::spawn(tls::watch());
+            // 定期将访问日志写入文件
+            tokio::spawn(access_log::run());
+            // 管理反向代理响应缓存
+            tokio::spawn(cache::run());
+
+            let mut config_changes = CONFIG_VERSION.subscribe();
+            let mut running: Vec<listener::Running> = Vec::new();
+            // 当前生效的监听地址、静态文件目录与统计路径
+            let mut applied = None;
 
             loop {
-                // 获取配置中的静态文件路径
-                let (static_root, stats_path, listen_addr) = {
+                // 获取配置中的静态文件路径与监听地址
+                let (static_root, stats_path, listeners, ssl, virtual_hosts) = {
                     let config = CONFIG.read().unwrap();
                     let static_root = config.server.static_root.clone();
                     let stats_path = config.features.stats_path.clone();
-                    let listen_addr: SocketAddr = config.server.listen_addr.parse().unwrap_or(([127, 0, 0, 1], 8082).into());
-                    (static_root, stats_path, listen_addr)
+                    let listeners = listener::listeners(&config.server);
+                    let ssl = if config.server.ssl_enabled { Some(config.server.clone()) } else { None };
+                    (static_root, stats_path, listeners, ssl, config.features.virtual_hosts)
+                };
+                // 监听地址无效时不启动服务器，而不是改用其他地址
+                let listeners = match listeners {
+                    Ok(listeners) => listeners,
+                    Err(e) if running.is_empty() => {
+                        eprintln!("监听配置无效，服务器未启动: {}", e);
+                        return;
+                    }
+                    Err(e) => {
+                        eprintln!("监听配置无效，继续使用原监听地址: {}", e);
+                        tokio::select! {
+                            _ = config_changes.changed() => continue,
+                            _ = shutdown_requested.changed() => break,
+                        }
+                    }
                 };
 
-                let static_files = Static::new(Path::new(&static_root));
-
-                let make_svc = make_service_fn(move |_conn| {
-                    let static_files = static_files.clone();
-                    let stats_path = stats_path.clone();
-                    async move {
-                        Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
-                            let static_files = static_files.clone();
-                            let stats_path = stats_path.clone();
-                            async move {
-                                // 增加请求数量
-                                increment_requests();
-                                
-                                // 处理 CORS 预检请求
-                                if req.method() == hyper::Method::OPTIONS {
-                                    let response = Response::builder()
-                                        .status(200)
-                                        .header("Access-Control-Allow-Origin", "*")
-                                        .header("Access-Control-Allow-Methods", "GET, POST, PUT, DELETE, OPTIONS")
-                                        .header("Access-Control-Allow-Headers", "Content-Type, Authorization")
-                                        .header("Access-Control-Max-Age", "86400")
-                                        .body(Body::from(""))
-                                        .unwrap();
-                                    return Ok::<_, Infallible>(response);
-                                }
-                                
-                                // 检查是否是监控端点
-                                if req.uri().path() == stats_path {
-                                    let monitoring_data = get_monitoring_data();
-                                    let json_data = serde_json::to_string_pretty(&monitoring_data).unwrap();
-                                    let response = Response::builder()
-                                        .header("Content-Type", "application/json")
-                                        .header("Access-Control-Allow-Origin", "*")
-                                        .header("Access-Control-Allow-Methods", "GET, OPTIONS")
-                                        .header("Access-Control-Allow-Headers", "Content-Type")
-                                        .body(Body::from(json_data))
-                                        .unwrap();
-                                    return Ok::<_, Infallible>(response);
-                                }
-                                
-                                // 检查是否是配置获取端点
-                                if req.uri().path() == "/api/config" && req.method() == hyper::Method::GET {
-                                    // 从全局配置中获取配置信息
-                                    let config = CONFIG.read().unwrap();
-                                    let json_data = serde_json::to_string_pretty(&*config).unwrap();
-                                    let response = Response::builder()
-                                        .header("Content-Type", "application/json")
-                                        .header("Access-Control-Allow-Origin", "*")
-                                        .header("Access-Control-Allow-Methods", "GET, OPTIONS")
-                                        .header("Access-Control-Allow-Headers", "Content-Type")
-                                        .body(Body::from(json_data))
-                                        .unwrap();
-                                    return Ok::<_, Infallible>(response);
-                                }
-                                
-                                // 检查是否是配置更新端点
-                                if req.uri().path() == "/api/config" && req.method() == hyper::Method::PUT {
-                                    // 读取请求体
-                                    let body_bytes = hyper::body::to_bytes(req.into_body()).await.unwrap();
-                                    let body_str = String::from_utf8(body_bytes.to_vec()).unwrap();
-                                    
-                                    // 解析配置
-                                    let new_config: ServerConfig = match serde_json::from_str(&body_str) {
-                                        Ok(config) => config,
-                                        Err(e) => {
-                                            let error_msg = format!("解析配置失败: {}", e);
-                                            eprintln!("{}", error_msg);
-                                            let response = Response::builder()
-                                                .status(400)
-                                                .header("Content-Type", "application/json")
-                                                .header("Access-Control-Allow-Origin", "*")
-                                                .body(Body::from(serde_json::json!({"error": error_msg}).to_string()))
-                                                .unwrap();
-                                            return Ok::<_, Infallible>(response);
-                                        }
-                                    };
-                                    
-                                    // 更新配置
-                                    match update_config(new_config) {
-                                        Ok(()) => {
-                                            let response = Response::builder()
-                                                .status(200)
-                                                .header("Content-Type", "application/json")
-                                                .header("Access-Control-Allow-Origin", "*")
-                                                .body(Body::from(serde_json::json!({"message": "配置更新成功"}).to_string()))
-                                                .unwrap();
-                                            return Ok::<_, Infallible>(response);
-                                        },
-                                        Err(e) => {
-                                            let error_msg = format!("更新配置失败: {}", e);
-                                            eprintln!("{}", error_msg);
-                                            let response = Response::builder()
-                                                .status(500)
-                                                .header("Content-Type", "application/json")
-                                                .header("Access-Control-Allow-Origin", "*")
-                                                .body(Body::from(serde_json::json!({"error": error_msg}).to_string()))
-                                                .unwrap();
-                                            return Ok::<_, Infallible>(response);
-                                        }
-                                    }
-                                }
-                                
-                                // 检查是否是API请求，需要转发到上游服务器
-                                let uri_path = req.uri().path();
-                                if uri_path.starts_with("/admin-api/") {
-                                    // 获取上游服务器列表
-                                    let servers = {
-                                        let config = CONFIG.read().unwrap();
-                                        config.upstream.servers.clone()
-                                    };
-                                    
-                                    // 简单的负载均衡 - 轮询选择第一个服务器
-                                    if !servers.is_empty() {
-                                        let upstream_server = &servers[0];
-                                        let upstream_addr = &upstream_server.address;
-                                        
-                                        // 克隆请求信息
-                                        let method = req.method().clone();
-                                        let headers = req.headers().clone();
-                                        let uri = req.uri().clone();
-                                        let body = req.into_body();
-                                        
-                                        // 构造转发URL
-                                        let forward_url = format!("http://{}{}",
-                                            upstream_addr,
-                                            uri.path_and_query()
-                                                .map(|p| p.as_str())
-                                                .unwrap_or("")
-                                        );
-                                        
-                                        // 创建客户端并转发请求
-                                        let client = hyper::Client::new();
-                                        let mut forward_req = hyper::Request::builder()
-                                            .method(method)
-                                            .uri(&forward_url)
-                                            .body(body)
-                                            .unwrap();
-                                        
-                                        // 复制头部信息
-                                        for (name, value) in headers.iter() {
-                                            // 不转发原始的Host头，让hyper自动设置正确的Host头
-                                            if name != hyper::header::HOST {
-                                                forward_req.headers_mut().insert(name, value.clone());
-                                            }
-                                        }
-                                        
-                                        // 设置正确的Host头为上游服务器地址
-                                        if let Ok(host_header) = hyper::header::HeaderValue::from_str(upstream_addr) {
-                                            forward_req.headers_mut().insert(hyper::header::HOST, host_header);
-                                        }
-                                        
-                                        // 发送请求到上游服务器
-                                        match client.request(forward_req).await {
-                                            Ok(upstream_response) => {
-                                                // 返回上游服务器的响应
-                                                return Ok::<_, Infallible>(upstream_response);
-                                            }
-                                            Err(e) => {
-                                                eprintln!("转发请求到上游服务器失败: {}", e);
-                                                let response = Response::builder()
-                                                    .status(502)
-                                                    .header("Access-Control-Allow-Origin", "*")
-                                                    .body(Body::from("Bad Gateway"))
-                                                    .unwrap();
-                                                return Ok::<_, Infallible>(response);
-                                            }
-                                        }
-                                    }
-                                }
-                                
-                                // 提供静态文件服务
-                                match static_files.serve(req).await {
-                                    Ok(mut response) => {
-                                        // 为静态文件响应也添加 CORS 头
-                                        response.headers_mut().insert(
-                                            "Access-Control-Allow-Origin",
-                                            hyper::header::HeaderValue::from_static("*")
-                                        );
-                                        response.headers_mut().insert(
-                                            "Access-Control-Allow-Methods",
-                                            hyper::header::HeaderValue::from_static("GET, OPTIONS")
-                                        );
-                                        response.headers_mut().insert(
-                                            "Access-Control-Allow-Headers",
-                                            hyper::header::HeaderValue::from_static("Content-Type")
-                                        );
-                                        Ok::<_, Infallible>(response)
-                                    },
-                                    Err(e) => {
-                                        eprintln!("Static file serving error: {}", e);
-                                        let response = Response::builder()
-                                            .status(500)
-                                            .header("Access-Control-Allow-Origin", "*")
-                                            .body(Body::from("Internal Server Error"))
-                                            .unwrap();
-                                        Ok(response)
-                                    }
-                                }
+                let settings = (listeners.clone(), static_root.clone(), stats_path.clone());
+                if applied.as_ref() != Some(&settings) {
+                    let result = match ssl.filter(|_| listeners.iter().any(|listener| listener.ssl)) {
+                        Some(server) => tls::reload(&server, virtual_hosts).map_err(|e| format!("HTTPS 启动失败: {}", e)),
+                        None => Ok(()),
+                    };
+                    let result = match result {
+                        Ok(()) => listener::apply(&mut running, listeners, static_root, stats_path).await,
+                        Err(e) => Err(e),
+                    };
+                    match result {
+                        Ok(()) => applied = Some(settings),
+                        Err(e) => {
+                            if running.is_empty() {
+                                eprintln!("Server error: {}", e);
+                            } else {
+                                eprintln!("Server error: {}，继续使用原监听地址", e);
                             }
-                        }))
+                            // 等待一段时间或配置再次变化后重试
+                            tokio::select! {
+                                _ = tokio::time::sleep(std::time::Duration::from_secs(5)) => continue,
+                                _ = config_changes.changed() => continue,
+                                _ = shutdown_requested.changed() => break,
+                            }
+                        }
                     }
-                });
-
-                let server = Server::bind(&listen_addr).serve(make_svc);
-                
-                println!("Server running on http://{}", listen_addr);
+                }
 
-                // 运行服务器直到出错
-                if let Err(e) = server.await {
-                    eprintln!("Server error: {}", e);
-                    // 等待一段时间后重试
-                    std::thread::sleep(std::time::Duration::from_secs(5));
+                // 等待配置更新或关闭服务器
+                tokio::select! {
+                    _ = config_changes.changed() => {}
+                    _ = shutdown_requested.changed() => break,
                 }
             }
+
+            // 停止接受新连接，等待进行中的请求完成
+            let timeout = {
+                let config = CONFIG.read().unwrap();
+                validated_duration(&config.server.shutdown_timeout, std::time::Duration::from_secs(30))
+            };
+            println!("正在关闭服务器，最多等待 {:?}", timeout);
+            listener::stop_all(&mut running).await;
+            if tokio::time::timeout(timeout, listener::drained()).await.is_err() {
+                eprintln!("等待超时，强制关闭 {} 个连接", CURRENT_CONNECTIONS.load(Ordering::Relaxed));
+            }
+            access_log::flush();
+            println!("服务器已关闭");
         });
     });
-}
\ No newline at end of file
+
+    ServerHandle {
+        shutdown,
+        thread: std::sync::Mutex::new(Some(thread)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn put_config(body: hyper::Body) -> (u16, String) {
+        let req = hyper::Request::put("/api/config").body(body).unwrap();
+        let conn = ConnectionInfo {
+            remote_addr: "127.0.0.1:50000".parse().unwrap(),
+            secure: true,
+            client_cert_subject: None,
+            server_name: None,
+        };
+        let response = route_request(req, conn, None, "./public".to_string(), "/stats".to_string())
+            .await
+            .unwrap();
+        let status = response.status().as_u16();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn removed_ssl_tls_feature_is_ignored() {
+        let mut config = serde_json::to_value(default_config()).unwrap();
+        config["features"]["ssl_tls"] = true.into();
+        let config: ServerConfig = serde_json::from_value(config).unwrap();
+        assert!(!config.server.ssl_enabled);
+    }
+
+    #[tokio::test]
+    async fn put_config_rejects_bad_requests_with_400() {
+        // 请求体不是 UTF-8 或不是 JSON
+        assert_eq!(put_config(hyper::Body::from(vec![0xff, 0xfe])).await.0, 400);
+        assert_eq!(put_config(hyper::Body::from("{")).await.0, 400);
+
+        // 读取请求体失败
+        let (sender, body) = hyper::Body::channel();
+        sender.abort();
+        assert_eq!(put_config(body).await.0, 400);
+
+        let mut config = serde_json::to_value(default_config()).unwrap();
+        config["upstream"]["load_balancing_algorithm"] = "random".into();
+        let (status, body) = put_config(hyper::Body::from(config.to_string())).await;
+        assert_eq!(status, 400);
+        assert!(body.contains("配置无效"), "{}", body);
+    }
+}
@@ -0,0 +1,746 @@
+// 监听地址
+//
+// server.listen 可以配置多个监听地址，每个地址单独启用 TLS 与 HTTP/2，所有监听地址共用
+// 同一个请求处理函数。未配置 listen 时按 listen_addr 与 ssl_listen_addr 生成监听地址。
+//
+// 配置更新后先绑定新增的监听地址，地址未变的监听套接字交给新的接受循环继续使用，
+// 旧的连接处理完当前请求后关闭，不再使用的监听地址停止接受连接并关闭套接字。
+// 地址未变但 ipv6_only 变化时无法同时绑定，先关闭原监听套接字再重新绑定。
+// 关闭服务器时以同样的方式停止所有监听地址，并等待所有连接关闭。
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinHandle;
+
+use crate::{handle_request, tls, ConnectionInfo, ServerSection, CONFIG, CURRENT_CONNECTIONS};
+
+// 等待 accept 的连接队列长度
+const BACKLOG: i32 = 1024;
+
+// 所有连接都已关闭时通知
+lazy_static::lazy_static! {
+    static ref CONNECTIONS_CLOSED: Notify = Notify::new();
+}
+
+// 统计当前连接数，连接结束时减一
+struct ConnectionGuard;
+
+impl ConnectionGuard {
+    fn new() -> Self {
+        CURRENT_CONNECTIONS.fetch_add(1, Ordering::AcqRel);
+        ConnectionGuard
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if CURRENT_CONNECTIONS.fetch_sub(1, Ordering::AcqRel) == 1 {
+            CONNECTIONS_CLOSED.notify_waiters();
+        }
+    }
+}
+
+/// 一个监听地址的配置
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Listener {
+    pub(crate) addr: SocketAddr,
+    pub(crate) ssl: bool,
+    pub(crate) http2: bool,
+    ipv6_only: bool,
+}
+
+impl Listener {
+    pub(crate) fn scheme(&self) -> &'static str {
+        if self.ssl {
+            "https"
+        } else {
+            "http"
+        }
+    }
+}
+
+/// 正在接受连接的监听地址
+pub(crate) struct Running {
+    listener: Listener,
+    static_root: String,
+    stats_path: String,
+    // 关闭后无法按这个配置重新绑定的监听地址，需要重启服务器才能生效
+    rejected: Option<Listener>,
+    // 发送后停止接受新连接，已建立的连接处理完当前请求后关闭
+    stop: watch::Sender<bool>,
+    task: JoinHandle<TcpListener>,
+}
+
+impl Running {
+    fn start(tcp_listener: TcpListener, listener: Listener, static_root: String, stats_path: String) -> Self {
+        println!("Server running on {}://{}", listener.scheme(), listener.addr);
+        let (stop, stopped) = watch::channel(false);
+        let task = tokio::spawn(serve(
+            tcp_listener,
+            listener.clone(),
+            static_root.clone(),
+            stats_path.clone(),
+            stopped,
+        ));
+        Running {
+            listener,
+            static_root,
+            stats_path,
+            rejected: None,
+            stop,
+            task,
+        }
+    }
+
+    /// 停止接受新连接，返回监听套接字。已建立的连接在后台处理完当前请求后关闭
+    async fn stop(self) -> Option<TcpListener> {
+        let _ = self.stop.send(true);
+        self.task.await.ok()
+    }
+
+    /// 停止接受新连接并关闭监听套接字。已在等待队列中的连接各处理一个请求后关闭，
+    /// 而不是随套接字一起被重置
+    async fn close(self) {
+        let listener = self.listener.clone();
+        let static_root = self.static_root.clone();
+        let stats_path = self.stats_path.clone();
+        let tcp_listener = match self.stop().await.map(TcpListener::into_std) {
+            Some(Ok(tcp_listener)) => tcp_listener,
+            _ => return,
+        };
+        // 监听套接字是非阻塞的，等待队列为空时返回 WouldBlock
+        while let Ok((stream, remote_addr)) = tcp_listener.accept() {
+            let stream = match stream.set_nonblocking(true).and_then(|_| TcpStream::from_std(stream)) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("接受连接失败: {}", e);
+                    continue;
+                }
+            };
+            spawn_connection(stream, remote_addr, &listener, static_root.clone(), stats_path.clone(), None);
+        }
+        println!("已停止监听 {}", listener.addr);
+    }
+}
+
+/// 按新的监听地址替换正在运行的监听地址。新增的地址全部绑定成功后才停止旧的接受循环，
+/// 绑定失败时返回错误并保持原监听地址不变。
+///
+/// 地址未变但 ipv6_only 变化的监听地址先关闭原监听套接字再重新绑定，重新绑定失败时
+/// 按原 ipv6_only 恢复监听并返回需要重启服务器的错误，之后的重试不再关闭监听套接字。
+/// 原监听套接字也无法恢复时返回错误，其余监听地址保留在 `running` 中继续接受连接
+pub(crate) async fn apply(
+    running: &mut Vec<Running>,
+    listeners: Vec<Listener>,
+    static_root: String,
+    stats_path: String,
+) -> Result<(), String> {
+    // 地址与 ipv6_only 都未变的监听套接字继续使用，地址已在监听的需要先关闭，其余的直接绑定
+    let reusable = |listener: &Listener, old: &Running| {
+        old.listener.addr == listener.addr && old.listener.ipv6_only == listener.ipv6_only
+    };
+    let mut bound = Vec::new();
+    for listener in &listeners {
+        if running.iter().any(|old| old.listener.addr == listener.addr) {
+            bound.push(None);
+        } else {
+            bound.push(Some(bind(listener)?));
+        }
+    }
+
+    let mut old = std::mem::take(running);
+    let mut result = Ok(());
+    for (listener, tcp_listener) in listeners.into_iter().zip(bound) {
+        let mut rejected = None;
+        let (tcp_listener, listener) = match tcp_listener {
+            Some(tcp_listener) => (tcp_listener, listener),
+            None => {
+                let index = old.iter().position(|old| old.listener.addr == listener.addr).unwrap();
+                let previous = old.swap_remove(index);
+                // 无法修改 ipv6_only 时按原选项监听，其他配置照常更新
+                let kept = Listener {
+                    ipv6_only: previous.listener.ipv6_only,
+                    ..listener.clone()
+                };
+                let restored = if reusable(&listener, &previous) || previous.rejected.as_ref() == Some(&listener) {
+                    if !reusable(&listener, &previous) {
+                        result = Err(restart_required(&listener));
+                        rejected = Some(listener);
+                    }
+                    match previous.stop().await {
+                        Some(tcp_listener) => Ok((tcp_listener, kept)),
+                        // 接受循环异常退出时重新绑定
+                        None => bind(&kept).map(|tcp_listener| (tcp_listener, kept)),
+                    }
+                } else {
+                    previous.close().await;
+                    match bind(&listener) {
+                        Ok(tcp_listener) => Ok((tcp_listener, listener)),
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            // 恢复原监听套接字，之后不再尝试这个配置
+                            result = Err(restart_required(&listener));
+                            rejected = Some(listener);
+                            bind(&kept).map(|tcp_listener| (tcp_listener, kept))
+                        }
+                    }
+                };
+                match restored {
+                    Ok(restored) => restored,
+                    Err(e) => {
+                        // 这个地址已无法监听，其余尚未处理的原监听地址继续运行，之后重试时重新绑定
+                        running.append(&mut old);
+                        return Err(e);
+                    }
+                }
+            }
+        };
+        let mut started = Running::start(tcp_listener, listener, static_root.clone(), stats_path.clone());
+        started.rejected = rejected;
+        running.push(started);
+    }
+
+    // 不再使用的监听地址
+    for old in old {
+        old.close().await;
+    }
+    result
+}
+
+fn restart_required(listener: &Listener) -> String {
+    format!("无法重新绑定监听地址 {} 以修改 ipv6_only，需要重启服务器", listener.addr)
+}
+
+/// 停止所有监听地址并关闭监听套接字，已建立的连接处理完当前请求后关闭
+pub(crate) async fn stop_all(running: &mut Vec<Running>) {
+    for running in running.drain(..) {
+        drop(running.stop().await);
+    }
+}
+
+/// 等待所有连接关闭
+pub(crate) async fn drained() {
+    loop {
+        let closed = CONNECTIONS_CLOSED.notified();
+        tokio::pin!(closed);
+        // 先登记再检查，避免错过检查之后的通知
+        closed.as_mut().enable();
+        if CURRENT_CONNECTIONS.load(Ordering::Acquire) == 0 {
+            return;
+        }
+        closed.await;
+    }
+}
+
+/// 按配置生成所有监听地址，地址无效时返回错误
+pub(crate) fn listeners(server: &ServerSection) -> Result<Vec<Listener>, String> {
+    let parse = |addr: &str| -> Result<SocketAddr, String> {
+        addr.parse().map_err(|e| format!("监听地址无效 {:?}: {}", addr, e))
+    };
+
+    let mut listeners = Vec::new();
+    if server.listen.is_empty() {
+        // 兼容原有配置：启用 SSL 且未配置 ssl_listen_addr 时 listen_addr 只提供 HTTPS
+        let https_only = server.ssl_enabled && server.ssl_listen_addr.is_empty();
+        listeners.push(Listener {
+            addr: parse(&server.listen_addr)?,
+            ssl: https_only,
+            http2: false,
+            ipv6_only: true,
+        });
+        if server.ssl_enabled && !server.ssl_listen_addr.is_empty() {
+            listeners.push(Listener {
+                addr: parse(&server.ssl_listen_addr)?,
+                ssl: true,
+                http2: false,
+                ipv6_only: true,
+            });
+        }
+    } else {
+        for listen in &server.listen {
+            listeners.push(Listener {
+                addr: parse(&listen.addr)?,
+                ssl: listen.ssl,
+                http2: listen.http2,
+                ipv6_only: listen.ipv6_only,
+            });
+        }
+    }
+    Ok(listeners)
+}
+
+/// 校验监听地址配置
+pub(crate) fn validate(server: &ServerSection) -> Result<(), String> {
+    let listeners = listeners(server)?;
+    for (i, listener) in listeners.iter().enumerate() {
+        if listeners[..i].iter().any(|other| other.addr == listener.addr) {
+            return Err(format!("监听地址重复: {}", listener.addr));
+        }
+        if listener.ssl && !server.ssl_enabled {
+            return Err(format!("监听地址 {} 启用了 ssl，但 server.ssl_enabled 未开启", listener.addr));
+        }
+    }
+    Ok(())
+}
+
+/// 绑定监听地址。IPv6 地址按 ipv6_only 决定是否同时接受 IPv4 连接
+pub(crate) fn bind(listener: &Listener) -> Result<TcpListener, String> {
+    let bind = || -> std::io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(listener.addr), Type::STREAM, Some(Protocol::TCP))?;
+        if listener.addr.is_ipv6() {
+            socket.set_only_v6(listener.ipv6_only)?;
+        }
+        socket.set_reuse_address(true)?;
+        socket.set_nodelay(true)?;
+        socket.bind(&listener.addr.into())?;
+        socket.listen(BACKLOG)?;
+        socket.set_nonblocking(true)?;
+        TcpListener::from_std(socket.into())
+    };
+    bind().map_err(|e| format!("监听 {} 失败: {}", listener.addr, e))
+}
+
+// 在监听地址上接受连接并处理请求，收到停止信号后返回监听套接字
+async fn serve(
+    tcp_listener: TcpListener,
+    listener: Listener,
+    static_root: String,
+    stats_path: String,
+    mut stop: watch::Receiver<bool>,
+) -> TcpListener {
+    loop {
+        let accepted = tokio::select! {
+            accepted = tcp_listener.accept() => accepted,
+            _ = stop.changed() => return tcp_listener,
+        };
+        let (stream, remote_addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // 例如文件描述符耗尽，稍后重试
+                eprintln!("接受连接失败: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        spawn_connection(stream, remote_addr, &listener, static_root.clone(), stats_path.clone(), Some(stop.clone()));
+    }
+}
+
+// 在后台处理一个连接。没有停止信号时只处理一个请求
+fn spawn_connection(
+    stream: TcpStream,
+    remote_addr: SocketAddr,
+    listener: &Listener,
+    static_root: String,
+    stats_path: String,
+    stop: Option<watch::Receiver<bool>>,
+) {
+    let _ = stream.set_nodelay(true);
+    let guard = ConnectionGuard::new();
+
+    let http2 = listener.http2;
+    if !listener.ssl {
+        let conn = ConnectionInfo {
+            remote_addr,
+            secure: false,
+            client_cert_subject: None,
+            server_name: None,
+        };
+        tokio::spawn(async move {
+            serve_connection(stream, conn, http2, static_root, stats_path, stop).await;
+            drop(guard);
+        });
+        return;
+    }
+
+    tokio::spawn(async move {
+        if let Some((stream, conn)) = tls::accept(stream, remote_addr, http2).await {
+            serve_connection(stream, conn, http2, static_root, stats_path, stop).await;
+        }
+        drop(guard);
+    });
+}
+
+// 在一个连接上处理请求。启用 HTTP/2 时同时接受 HTTP/1.1 与 HTTP/2 (TLS 通过 ALPN 协商，
+// 明文连接通过 h2c 前言识别)。收到停止信号后处理完当前请求再关闭连接，
+// 没有停止信号时处理完一个请求就关闭 HTTP/1.1 连接
+async fn serve_connection<S>(
+    stream: S,
+    conn: ConnectionInfo,
+    http2: bool,
+    static_root: String,
+    stats_path: String,
+    stop: Option<watch::Receiver<bool>>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let remote_addr = conn.remote_addr;
+    let service = service_fn(move |req| {
+        let secure = conn.secure;
+        let response = handle_request(req, conn.clone(), static_root.clone(), stats_path.clone());
+        async move {
+            let mut response = response.await?;
+            tls::add_hsts_header(&CONFIG.read().unwrap().server, secure, &mut response);
+            Ok::<_, Infallible>(response)
+        }
+    });
+
+    let mut http = Http::new();
+    if !http2 {
+        http.http1_only(true);
+    }
+    if stop.is_none() {
+        http.http1_keep_alive(false);
+    }
+    let stopped = async move {
+        match stop {
+            Some(mut stop) => {
+                let _ = stop.changed().await;
+            }
+            None => std::future::pending().await,
+        }
+    };
+    let connection = http.serve_connection(stream, service);
+    tokio::pin!(connection);
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = stopped => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("连接错误 {}: {}", remote_addr, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    fn dual_stack(port: u16, ipv6_only: bool) -> Listener {
+        Listener {
+            addr: SocketAddr::from(([0u16; 8], port)),
+            ssl: false,
+            http2: false,
+            ipv6_only,
+        }
+    }
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("[::]:0").unwrap().local_addr().unwrap().port()
+    }
+
+    async fn apply_one(running: &mut Vec<Running>, listener: Listener) -> Result<(), String> {
+        apply(running, vec![listener], "./public".to_string(), "/status".to_string()).await
+    }
+
+    #[tokio::test]
+    async fn ipv6_only_change_rebinds_same_address() {
+        let port = free_port();
+        let mut running = Vec::new();
+        apply_one(&mut running, dual_stack(port, true)).await.unwrap();
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+
+        apply_one(&mut running, dual_stack(port, false)).await.unwrap();
+        assert_eq!(running.len(), 1);
+        assert!(!running[0].listener.ipv6_only);
+        TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        TcpStream::connect(("::1", port)).await.unwrap();
+
+        stop_all(&mut running).await;
+    }
+
+    #[tokio::test]
+    async fn failed_rebind_keeps_old_options_and_requires_restart() {
+        let port = free_port();
+        let mut running = Vec::new();
+        apply_one(&mut running, dual_stack(port, true)).await.unwrap();
+        // 占用 IPv4 地址后无法改为同时接受 IPv4 连接
+        let ipv4 = std::net::TcpListener::bind(("0.0.0.0", port)).unwrap();
+
+        for _ in 0..2 {
+            let err = apply_one(&mut running, dual_stack(port, false)).await.unwrap_err();
+            assert!(err.contains("需要重启服务器"), "{}", err);
+            assert_eq!(running.len(), 1);
+            assert!(running[0].listener.ipv6_only);
+            TcpStream::connect(("::1", port)).await.unwrap();
+        }
+
+        // 改回原配置后不再报错
+        drop(ipv4);
+        apply_one(&mut running, dual_stack(port, true)).await.unwrap();
+        assert!(running[0].rejected.is_none());
+        stop_all(&mut running).await;
+    }
+
+    #[tokio::test]
+    async fn queued_connections_served_when_closed() {
+        let listener = Listener {
+            addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            ssl: false,
+            http2: false,
+            ipv6_only: true,
+        };
+        let tcp_listener = bind(&listener).unwrap();
+        let addr = tcp_listener.local_addr().unwrap();
+        // 不接受连接的接受循环，连接留在等待队列中
+        let (stop, _) = watch::channel(false);
+        let running = Running {
+            listener,
+            static_root: "./public".to_string(),
+            stats_path: "/status".to_string(),
+            rejected: None,
+            stop,
+            task: tokio::spawn(async move { tcp_listener }),
+        };
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"GET /status HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        running.close().await;
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 "), "{}", response);
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    fn server_with_listen(listen: &str) -> ServerSection {
+        let mut server = crate::default_config().server;
+        server.listen = serde_json::from_str(listen).unwrap();
+        server
+    }
+
+    fn addr(listener: &Listener) -> String {
+        listener.addr.to_string()
+    }
+
+    #[test]
+    fn legacy_listen_addr_used_without_listen() {
+        let mut server = crate::default_config().server;
+        server.listen_addr = "127.0.0.1:8080".to_string();
+        let found = listeners(&server).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!((addr(&found[0]), found[0].ssl), ("127.0.0.1:8080".to_string(), false));
+
+        // 启用 SSL 但未配置 ssl_listen_addr 时 listen_addr 只提供 HTTPS
+        server.ssl_enabled = true;
+        let found = listeners(&server).unwrap();
+        assert_eq!(found.len(), 1);
+        assert!(found[0].ssl);
+
+        server.ssl_listen_addr = "[::]:8443".to_string();
+        let found = listeners(&server).unwrap();
+        assert_eq!(
+            found.iter().map(|l| (addr(l), l.ssl)).collect::<Vec<_>>(),
+            vec![("127.0.0.1:8080".to_string(), false), ("[::]:8443".to_string(), true)]
+        );
+        assert!(found.iter().all(|l| !l.http2 && l.ipv6_only));
+
+        // 配置了 listen 时不再使用 listen_addr 与 ssl_listen_addr
+        server.listen = serde_json::from_str(r#"[{"addr": "0.0.0.0:9000"}]"#).unwrap();
+        let found = listeners(&server).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(addr(&found[0]), "0.0.0.0:9000");
+
+        server.listen.clear();
+        server.listen_addr = "localhost:8080".to_string();
+        assert!(listeners(&server).is_err());
+    }
+
+    #[test]
+    fn listen_entries_keep_their_own_flags() {
+        let mut server = server_with_listen(
+            r#"[
+                {"addr": "0.0.0.0:80"},
+                {"addr": "0.0.0.0:443", "ssl": true, "http2": true},
+                {"addr": "[::]:8080", "http2": true, "ipv6_only": false}
+            ]"#,
+        );
+        server.ssl_enabled = true;
+        let found = listeners(&server).unwrap();
+        assert_eq!(
+            found.iter().map(|l| (addr(l), l.ssl, l.http2, l.ipv6_only)).collect::<Vec<_>>(),
+            vec![
+                ("0.0.0.0:80".to_string(), false, false, true),
+                ("0.0.0.0:443".to_string(), true, true, true),
+                ("[::]:8080".to_string(), false, true, false),
+            ]
+        );
+        assert!(validate(&server).is_ok());
+
+        server.ssl_enabled = false;
+        let err = validate(&server).unwrap_err();
+        assert!(err.contains("0.0.0.0:443"), "{}", err);
+
+        let server = server_with_listen(r#"[{"addr": "0.0.0.0:80"}, {"addr": "0.0.0.0:80", "http2": true}]"#);
+        let err = validate(&server).unwrap_err();
+        assert!(err.contains("监听地址重复"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn dual_stack_listen_accepts_ipv4_only_when_ipv6_only_disabled() {
+        let dual = free_port();
+        let v6 = free_port();
+        let server = server_with_listen(&format!(
+            r#"[{{"addr": "[::]:{}", "ipv6_only": false}}, {{"addr": "[::]:{}"}}]"#,
+            dual, v6
+        ));
+        let mut running = Vec::new();
+        apply(&mut running, listeners(&server).unwrap(), "./public".to_string(), "/status".to_string())
+            .await
+            .unwrap();
+
+        TcpStream::connect(("127.0.0.1", dual)).await.unwrap();
+        TcpStream::connect(("::1", dual)).await.unwrap();
+        TcpStream::connect(("::1", v6)).await.unwrap();
+        assert!(TcpStream::connect(("127.0.0.1", v6)).await.is_err());
+
+        stop_all(&mut running).await;
+    }
+
+    #[tokio::test]
+    async fn http2_and_ssl_apply_only_to_their_listener() {
+        let (h2, h1, https) = (free_port(), free_port(), free_port());
+        let server = server_with_listen(&format!(
+            r#"[
+                {{"addr": "127.0.0.1:{}", "http2": true}},
+                {{"addr": "127.0.0.1:{}"}},
+                {{"addr": "127.0.0.1:{}", "ssl": true}}
+            ]"#,
+            h2, h1, https
+        ));
+        let mut running = Vec::new();
+        apply(&mut running, listeners(&server).unwrap(), "./public".to_string(), "/status".to_string())
+            .await
+            .unwrap();
+
+        // 明文 HTTP/2 (h2c) 只在开启 http2 的监听地址上可用
+        let client = hyper::Client::builder().http2_only(true).build_http::<hyper::Body>();
+        let uri = |port: u16| format!("http://127.0.0.1:{}/status", port).parse::<hyper::Uri>().unwrap();
+        let response = client.get(uri(h2)).await.unwrap();
+        assert_eq!(response.version(), hyper::Version::HTTP_2);
+        assert!(client.get(uri(h1)).await.is_err());
+
+        // HTTP/1.1 在所有明文监听地址上可用
+        let client = hyper::Client::new();
+        for port in [h2, h1] {
+            let response = client.get(uri(port)).await.unwrap();
+            assert_eq!(response.version(), hyper::Version::HTTP_11);
+        }
+
+        // HTTPS 监听地址不处理明文请求
+        let mut stream = TcpStream::connect(("127.0.0.1", https)).await.unwrap();
+        stream.write_all(b"GET /status HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        assert!(!response.starts_with(b"HTTP/"));
+
+        stop_all(&mut running).await;
+    }
+
+    fn local(port: u16) -> Listener {
+        Listener {
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+            ssl: false,
+            http2: false,
+            ipv6_only: true,
+        }
+    }
+
+    async fn status(port: u16) -> u16 {
+        let uri = format!("http://127.0.0.1:{}/status", port).parse().unwrap();
+        hyper::Client::new().get(uri).await.unwrap().status().as_u16()
+    }
+
+    #[tokio::test]
+    async fn occupied_new_address_keeps_running_listeners() {
+        let port = free_port();
+        let mut running = Vec::new();
+        apply_one(&mut running, local(port)).await.unwrap();
+
+        let occupied = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let busy = occupied.local_addr().unwrap().port();
+        let err = apply(&mut running, vec![local(port), local(busy)], "./public".to_string(), "/status".to_string())
+            .await
+            .unwrap_err();
+        assert!(err.contains(&busy.to_string()), "{}", err);
+        assert_eq!(running.len(), 1);
+        assert_eq!(status(port).await, 200);
+
+        stop_all(&mut running).await;
+    }
+
+    #[tokio::test]
+    async fn failed_rebind_keeps_other_listeners() {
+        let (added, kept) = (free_port(), free_port());
+        let mut running = Vec::new();
+        apply_one(&mut running, local(kept)).await.unwrap();
+
+        // 接受循环异常退出，且地址已被其他程序占用，无法重新绑定
+        let occupied = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let lost = local(occupied.local_addr().unwrap().port());
+        let task = tokio::spawn(std::future::pending());
+        task.abort();
+        let (stop, _) = watch::channel(false);
+        running.push(Running {
+            listener: lost.clone(),
+            static_root: "./public".to_string(),
+            stats_path: "/status".to_string(),
+            rejected: None,
+            stop,
+            task,
+        });
+
+        let listeners = vec![local(added), lost, local(kept)];
+        assert!(apply(&mut running, listeners, "./public".to_string(), "/status".to_string()).await.is_err());
+        let mut ports = running.iter().map(|running| running.listener.addr.port()).collect::<Vec<_>>();
+        ports.sort();
+        let mut expected = vec![added, kept];
+        expected.sort();
+        assert_eq!(ports, expected);
+        assert_eq!(status(added).await, 200);
+        assert_eq!(status(kept).await, 200);
+
+        stop_all(&mut running).await;
+    }
+
+    #[tokio::test]
+    async fn in_flight_request_completes_after_shutdown_starts() {
+        let port = free_port();
+        let mut running = Vec::new();
+        apply_one(&mut running, local(port)).await.unwrap();
+
+        // 请求体还未发送完，请求仍在处理中
+        let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        client
+            .write_all(b"PUT /api/config HTTP/1.1\r\nHost: localhost\r\nContent-Length: 8\r\n\r\nnot")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        stop_all(&mut running).await;
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+        assert!(tokio::time::timeout(Duration::from_millis(100), drained()).await.is_err());
+
+        // 发送剩余的请求体后得到响应，之后连接关闭
+        client.write_all(b" json").await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+        tokio::time::timeout(Duration::from_secs(5), drained()).await.unwrap();
+    }
+}
@@ -0,0 +1,330 @@
+// location 匹配与路径重写
+//
+// 匹配顺序与 nginx 相同：先查找精确匹配，再记录最长的前缀匹配，
+// 然后按配置顺序检查正则匹配，正则都不匹配时使用最长的前缀匹配。
+
+use std::sync::OnceLock;
+
+use hyper::{Body, Request, Uri};
+use regex::Regex;
+
+use crate::{LocationConfig, ServerConfig};
+
+/// 随配置一起保存的已编译正则表达式
+///
+/// 第一次使用 (通常是校验配置) 时编译，之后同一配置中的请求直接使用。
+/// 配置更新后新配置重新编译，旧的正则随旧配置一起释放。
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CompiledRegexes(OnceLock<Vec<Result<Regex, String>>>);
+
+impl CompiledRegexes {
+    /// 按顺序返回各个正则的编译结果
+    pub(crate) fn get<'a>(&self, patterns: impl IntoIterator<Item = &'a str>) -> &[Result<Regex, String>] {
+        self.0.get_or_init(|| {
+            patterns
+                .into_iter()
+                .map(|pattern| Regex::new(pattern).map_err(|e| format!("正则表达式 {:?} 无效: {}", pattern, e)))
+                .collect()
+        })
+    }
+}
+
+// regex 类型 location 的正则，编译失败的配置在校验时已被拒绝
+fn location_regex(location: &LocationConfig) -> Result<&Regex, String> {
+    match location.regex.get([location.path.as_str()]).first() {
+        Some(Ok(regex)) => Ok(regex),
+        Some(Err(e)) => Err(e.clone()),
+        None => Err(format!("正则表达式 {:?} 无效", location.path)),
+    }
+}
+
+// proxy_next_upstream 可用的条件，与 nginx 同名
+const NEXT_UPSTREAM_CONDITIONS: &[&str] = &[
+    "error",
+    "timeout",
+    "http_500",
+    "http_502",
+    "http_503",
+    "http_504",
+    "http_403",
+    "http_404",
+    "http_429",
+    "non_idempotent",
+    "off",
+];
+
+/// 校验 location 配置
+pub(crate) fn validate(config: &ServerConfig) -> Result<(), String> {
+    validate_locations(config, &config.locations)
+}
+
+/// 校验一组 location，虚拟主机的 locations 也使用同样的规则
+pub(crate) fn validate_locations(config: &ServerConfig, locations: &[LocationConfig]) -> Result<(), String> {
+    for location in locations {
+        match location.match_type.as_str() {
+            "prefix" | "exact" => {
+                if !location.path.starts_with('/') {
+                    return Err(format!("location 路径必须以 / 开头: {}", location.path));
+                }
+            }
+            "regex" => {
+                location_regex(location)?;
+            }
+            other => {
+                return Err(format!(
+                    "location {} 的匹配方式无效: {}，可选值: prefix, exact, regex",
+                    location.path, other
+                ))
+            }
+        }
+
+        match location.handler.as_str() {
+            "proxy" => {
+                if location.upstream.is_empty()
+                    && location.proxy_pass.is_empty()
+                    && config.server.backend_addr.is_empty()
+                {
+                    return Err(format!(
+                        "代理 location {} 需要配置 upstream 或 proxy_pass (或 server.backend_addr)",
+                        location.path
+                    ));
+                }
+                if !location.upstream.is_empty() && crate::find_upstream_group(config, &location.upstream).is_none() {
+                    return Err(format!("location {} 引用了不存在的上游服务器组: {}", location.path, location.upstream));
+                }
+                if location.proxy_pass.starts_with("https://") {
+                    return Err(format!("location {} 的 proxy_pass 暂不支持 https", location.path));
+                }
+                for condition in &location.proxy_next_upstream {
+                    if !NEXT_UPSTREAM_CONDITIONS.contains(&condition.as_str()) {
+                        return Err(format!(
+                            "location {} 的 proxy_next_upstream 条件无效: {}，可选值: {}",
+                            location.path,
+                            condition,
+                            NEXT_UPSTREAM_CONDITIONS.join(", ")
+                        ));
+                    }
+                }
+                if location.proxy_next_upstream.len() > 1 && location.proxy_next_upstream.iter().any(|c| c == "off") {
+                    return Err(format!("location {} 的 proxy_next_upstream 中 off 不能与其他条件同时使用", location.path));
+                }
+            }
+            "static" => {}
+            other => {
+                return Err(format!(
+                    "location {} 的处理方式无效: {}，可选值: proxy, static",
+                    location.path, other
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 查找与请求路径匹配的 location
+pub(crate) fn find<'a>(locations: &'a [LocationConfig], path: &str) -> Option<&'a LocationConfig> {
+    let mut longest_prefix: Option<&LocationConfig> = None;
+
+    for location in locations {
+        match location.match_type.as_str() {
+            "exact" if location.path == path => return Some(location),
+            "prefix" if path.starts_with(&location.path) => {
+                match longest_prefix {
+                    Some(longest) if longest.path.len() >= location.path.len() => {}
+                    _ => longest_prefix = Some(location),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for location in locations.iter().filter(|location| location.match_type == "regex") {
+        if location_regex(location).is_ok_and(|regex| regex.is_match(path)) {
+            return Some(location);
+        }
+    }
+
+    longest_prefix
+}
+
+/// 按 location 的 strip_prefix / rewrite 配置重写请求路径，保留原来的查询参数
+///
+/// 与 nginx 相同，重写结果中已有查询参数时用 & 追加原来的查询参数，
+/// 重写结果以 ? 结尾时丢弃原来的查询参数。
+pub(crate) fn rewrite_request(location: &LocationConfig, req: &mut Request<Body>) {
+    let path = req.uri().path();
+    let new_path = rewrite_path(location, path);
+    if new_path == path {
+        return;
+    }
+
+    let path_and_query = match (new_path.strip_suffix('?'), req.uri().query()) {
+        (Some(path), _) => path.to_string(),
+        (None, Some(query)) if new_path.contains('?') => format!("{}&{}", new_path, query),
+        (None, Some(query)) => format!("{}?{}", new_path, query),
+        (None, None) => new_path,
+    };
+    let mut uri_parts = req.uri().clone().into_parts();
+    match path_and_query.parse() {
+        Ok(path_and_query) => uri_parts.path_and_query = Some(path_and_query),
+        Err(e) => {
+            eprintln!("重写后的路径无效 {:?}: {}", path_and_query, e);
+            return;
+        }
+    }
+    if let Ok(uri) = Uri::from_parts(uri_parts) {
+        *req.uri_mut() = uri;
+    }
+}
+
+fn rewrite_path(location: &LocationConfig, path: &str) -> String {
+    let rewritten = if location.match_type == "regex" {
+        let regex = match location_regex(location) {
+            Ok(regex) => regex,
+            Err(_) => return path.to_string(),
+        };
+        if !location.rewrite.is_empty() {
+            // 正则 location 的 rewrite 支持 $1、$name 等捕获组引用
+            regex.replace(path, location.rewrite.as_str()).into_owned()
+        } else if location.strip_prefix {
+            regex.replace(path, "").into_owned()
+        } else {
+            return path.to_string();
+        }
+    } else {
+        // 前缀与精确匹配时，用 rewrite 替换匹配到的前缀，strip_prefix 则直接去掉前缀
+        let rest = &path[location.path.len().min(path.len())..];
+        if !location.rewrite.is_empty() {
+            format!("{}{}", location.rewrite, rest)
+        } else if location.strip_prefix {
+            rest.to_string()
+        } else {
+            return path.to_string();
+        }
+    };
+
+    if rewritten.starts_with('/') {
+        rewritten
+    } else {
+        format!("/{}", rewritten)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(json: serde_json::Value) -> LocationConfig {
+        let mut location = serde_json::json!({"handler": "static"});
+        location.as_object_mut().unwrap().extend(json.as_object().unwrap().clone());
+        serde_json::from_value(location).unwrap()
+    }
+
+    fn found<'a>(locations: &'a [LocationConfig], path: &str) -> Option<&'a str> {
+        find(locations, path).map(|location| location.path.as_str())
+    }
+
+    #[test]
+    fn exact_match_wins() {
+        let locations = vec![
+            location(serde_json::json!({"path": "/"})),
+            location(serde_json::json!({"path": "/index.html", "match_type": "exact"})),
+            location(serde_json::json!({"path": "\\.html$", "match_type": "regex"})),
+        ];
+        assert_eq!(found(&locations, "/index.html"), Some("/index.html"));
+        // 精确匹配不匹配子路径
+        assert_eq!(found(&locations, "/index.html/x"), Some("/"));
+    }
+
+    #[test]
+    fn longest_prefix_match() {
+        let locations = vec![
+            location(serde_json::json!({"path": "/"})),
+            location(serde_json::json!({"path": "/api/v1/"})),
+            location(serde_json::json!({"path": "/api/"})),
+        ];
+        assert_eq!(found(&locations, "/api/v1/users"), Some("/api/v1/"));
+        assert_eq!(found(&locations, "/api/v2/users"), Some("/api/"));
+        assert_eq!(found(&locations, "/other"), Some("/"));
+        assert_eq!(found(&locations[1..], "/other"), None);
+    }
+
+    #[test]
+    fn regex_match_before_prefix() {
+        let locations = vec![
+            location(serde_json::json!({"path": "/static/"})),
+            location(serde_json::json!({"path": "\\.(png|jpg)$", "match_type": "regex"})),
+            location(serde_json::json!({"path": "^/static/.*\\.png$", "match_type": "regex"})),
+        ];
+        // 正则按配置顺序检查，第一个匹配的正则优先于前缀匹配
+        assert_eq!(found(&locations, "/static/a.png"), Some("\\.(png|jpg)$"));
+        assert_eq!(found(&locations, "/static/a.css"), Some("/static/"));
+        assert_eq!(found(&locations, "/b.jpg"), Some("\\.(png|jpg)$"));
+    }
+
+    #[test]
+    fn invalid_regex_rejected() {
+        let mut config: ServerConfig = crate::default_config();
+        config.locations = vec![location(serde_json::json!({"path": "(", "match_type": "regex"}))];
+        assert!(validate(&config).is_err());
+    }
+
+    #[test]
+    fn unknown_upstream_group_rejected() {
+        let mut config: ServerConfig = crate::default_config();
+        config.upstreams.insert("api".to_string(), crate::UpstreamSection::default());
+        config.locations = vec![location(serde_json::json!({"path": "/api/", "handler": "proxy", "upstream": "api"}))];
+        assert!(validate(&config).is_ok());
+
+        config.locations = vec![location(serde_json::json!({"path": "/api/", "handler": "proxy", "upstream": "missing"}))];
+        let error = validate(&config).unwrap_err();
+        assert!(error.contains("missing"), "{}", error);
+    }
+
+    #[test]
+    fn strip_prefix_and_rewrite() {
+        let strip = location(serde_json::json!({"path": "/api/", "strip_prefix": true}));
+        assert_eq!(rewrite_path(&strip, "/api/users"), "/users");
+        assert_eq!(rewrite_path(&strip, "/api/"), "/");
+
+        let rewrite = location(serde_json::json!({"path": "/old/", "rewrite": "/new/"}));
+        assert_eq!(rewrite_path(&rewrite, "/old/page"), "/new/page");
+
+        let regex = location(serde_json::json!({
+            "path": "^/users/(?P<id>[0-9]+)$", "match_type": "regex", "rewrite": "/api/user?id=$id"
+        }));
+        assert_eq!(rewrite_path(&regex, "/users/42"), "/api/user?id=42");
+
+        let regex_strip = location(serde_json::json!({"path": "^/v[0-9]+", "match_type": "regex", "strip_prefix": true}));
+        assert_eq!(rewrite_path(&regex_strip, "/v2/items"), "/items");
+
+        let unchanged = location(serde_json::json!({"path": "/keep/"}));
+        assert_eq!(rewrite_path(&unchanged, "/keep/x"), "/keep/x");
+    }
+
+    #[test]
+    fn rewrite_keeps_query() {
+        let strip = location(serde_json::json!({"path": "/api/", "strip_prefix": true}));
+        let mut req = Request::get("http://example.com/api/search?q=1").body(Body::empty()).unwrap();
+        rewrite_request(&strip, &mut req);
+        assert_eq!(req.uri().path(), "/search");
+        assert_eq!(req.uri().query(), Some("q=1"));
+    }
+
+    #[test]
+    fn rewrite_with_query_joins_original_query() {
+        let rewritten = |rewrite: &str, uri: &str| {
+            let regex = location(serde_json::json!({
+                "path": "^/item/([0-9]+)$", "match_type": "regex", "rewrite": rewrite
+            }));
+            let mut req = Request::get(uri).body(Body::empty()).unwrap();
+            rewrite_request(&regex, &mut req);
+            req.uri().path_and_query().unwrap().as_str().to_string()
+        };
+        assert_eq!(rewritten("/item?id=$1", "/item/7?page=2"), "/item?id=7&page=2");
+        assert_eq!(rewritten("/item?id=$1", "/item/7"), "/item?id=7");
+        // 以 ? 结尾时丢弃原来的查询参数
+        assert_eq!(rewritten("/item/$1?", "/item/7?page=2"), "/item/7");
+        assert_eq!(rewritten("/show/$1", "/item/7?page=2"), "/show/7?page=2");
+    }
+}
@@ -0,0 +1,1006 @@
+// 反向代理
+//
+// 将请求转发到 location 指定的目标。目标为上游服务器组时由该组的负载均衡选择服务器，
+// 连接或响应失败时记录失败次数，并在其他可用的上游服务器上重试。
+// 转发时移除两个方向上的逐跳头部，并按 location 的配置附加 X-Forwarded-For 等标准代理头部。
+// 每个上游服务器组使用一个共享的连接池客户端，超时时返回 504 Gateway Timeout。
+// 幂等请求按 location 的 proxy_next_upstream 配置在失败时改用组内的下一个服务器。
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::body::{Bytes, HttpBody};
+use hyper::client::connect::capture_connection;
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderMap, HeaderValue};
+use hyper::http::request::Parts;
+use hyper::{Body, Client, Method, Request, Response, Uri};
+
+use crate::balancer::{self, SelectedPeer};
+use crate::{find_upstream_group, validated_duration, ConnectionInfo, LocationConfig, UpstreamSection, CONFIG};
+
+// 各上游服务器组共享的 HTTP 客户端，连接在请求之间复用。
+// 直接转发 (未配置 upstream 的 location) 使用组名为空字符串的客户端。
+lazy_static::lazy_static! {
+    static ref CLIENTS: Mutex<HashMap<String, (ProxyTimeouts, Client<HttpConnector>)>> = Mutex::new(HashMap::new());
+}
+
+// 每次向上游发送的请求体分块大小，发送超时按块计算
+const SEND_CHUNK_SIZE: usize = 64 * 1024;
+
+// 上游服务器组的超时与连接池设置
+#[derive(Clone, Debug, PartialEq)]
+struct ProxyTimeouts {
+    connect: Duration,
+    read: Duration,
+    send: Duration,
+    keepalive: usize,
+    keepalive_timeout: Duration,
+}
+
+impl ProxyTimeouts {
+    // 默认值与 nginx 相同，为 60 秒
+    fn new(upstream: &UpstreamSection) -> Self {
+        let parse = |value: &str| validated_duration(value, Duration::from_secs(60));
+        ProxyTimeouts {
+            connect: parse(&upstream.proxy_connect_timeout),
+            read: parse(&upstream.proxy_read_timeout),
+            send: parse(&upstream.proxy_send_timeout),
+            keepalive: upstream.keepalive,
+            keepalive_timeout: parse(&upstream.keepalive_timeout),
+        }
+    }
+}
+
+// 获取服务器组共享的客户端，设置变化时重新创建
+fn shared_client(group: &str, timeouts: &ProxyTimeouts) -> Client<HttpConnector> {
+    let mut clients = CLIENTS.lock().unwrap();
+    if let Some((current, client)) = clients.get(group) {
+        if current == timeouts {
+            return client.clone();
+        }
+    }
+
+    let mut connector = HttpConnector::new();
+    connector.set_connect_timeout(Some(timeouts.connect));
+    connector.set_nodelay(true);
+    let client = Client::builder()
+        .pool_max_idle_per_host(timeouts.keepalive)
+        .pool_idle_timeout(timeouts.keepalive_timeout)
+        .build(connector);
+    clients.insert(group.to_string(), (timeouts.clone(), client.clone()));
+    client
+}
+
+/// 按配置同步各服务器组的客户端，移除已删除或设置已变化的组，释放其空闲连接
+pub(crate) fn sync_clients(upstreams: &[(String, UpstreamSection)]) {
+    let mut clients = CLIENTS.lock().unwrap();
+    clients.retain(|group, (timeouts, _)| {
+        // 直接转发的客户端不属于任何服务器组
+        group.is_empty()
+            || upstreams
+                .iter()
+                .any(|(name, upstream)| name == group && ProxyTimeouts::new(upstream) == *timeouts)
+    });
+}
+
+// 转发失败的原因，超时返回 504，其他错误返回 502
+enum ProxyError {
+    Timeout(String),
+    Failed(String),
+}
+
+impl std::fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProxyError::Timeout(message) | ProxyError::Failed(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// 没有从上游获得响应时，代理生成的错误响应在 extensions 中记录失败原因
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum UpstreamFailure {
+    Error,
+    Timeout,
+}
+
+impl ProxyError {
+    fn to_response(&self) -> Response<Body> {
+        let (mut response, failure) = match self {
+            ProxyError::Timeout(_) => (error_response(504, "Gateway Timeout"), UpstreamFailure::Timeout),
+            ProxyError::Failed(_) => (error_response(502, "Bad Gateway"), UpstreamFailure::Error),
+        };
+        response.extensions_mut().insert(failure);
+        response
+    }
+}
+
+// 转发给上游的请求体
+enum RequestBody {
+    // 已完整缓存，可在重试时重新发送
+    Buffered(Bytes),
+    // 已读取的部分与剩余的请求体，只能发送一次
+    Streaming(Bytes, Body),
+}
+
+impl RequestBody {
+    // 为重试缓存不超过 limit 字节的请求体，超过时返回流式请求体
+    async fn read(mut body: Body, content_length: Option<u64>, limit: usize) -> Result<Self, hyper::Error> {
+        if content_length.is_some_and(|length| length > limit as u64) {
+            return Ok(RequestBody::Streaming(Bytes::new(), body));
+        }
+
+        let mut buffer = Vec::new();
+        while let Some(chunk) = body.data().await {
+            buffer.extend_from_slice(&chunk?);
+            if buffer.len() > limit {
+                return Ok(RequestBody::Streaming(Bytes::from(buffer), body));
+            }
+        }
+        Ok(RequestBody::Buffered(Bytes::from(buffer)))
+    }
+}
+
+// RFC 9110 中定义为幂等的方法，失败后可以安全地发送到另一个上游服务器
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS | Method::TRACE
+    )
+}
+
+// 按 location 的 proxy_next_upstream 配置判断请求是否允许重试
+fn retry_allowed(location: &LocationConfig, method: &Method) -> bool {
+    let conditions = &location.proxy_next_upstream;
+    if conditions.is_empty() || conditions.iter().any(|c| c == "off") {
+        return false;
+    }
+    is_idempotent(method) || conditions.iter().any(|c| c == "non_idempotent")
+}
+
+fn retry_on_error(location: &LocationConfig, error: &ProxyError) -> bool {
+    let condition = match error {
+        ProxyError::Timeout(_) => "timeout",
+        ProxyError::Failed(_) => "error",
+    };
+    location.proxy_next_upstream.iter().any(|c| c == condition)
+}
+
+fn retry_on_status(location: &LocationConfig, status: u16) -> bool {
+    let condition = format!("http_{}", status);
+    location.proxy_next_upstream.contains(&condition)
+}
+
+/// 将请求转发到 location 配置的上游服务器组或地址
+pub(crate) async fn forward(req: Request<Body>, conn: &ConnectionInfo, location: &LocationConfig) -> Response<Body> {
+    let (mut parts, body) = req.into_parts();
+    let content_length = parts
+        .headers
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    remove_hop_by_hop_headers(&mut parts.headers);
+    add_proxy_headers(&mut parts.headers, &parts.uri, location, conn);
+
+    // 只有可能重试时才缓存请求体，否则直接流式转发
+    let retry = !location.upstream.is_empty() && retry_allowed(location, &parts.method);
+    let buffer_limit = if retry { location.proxy_next_upstream_body_size } else { 0 };
+    let body = match RequestBody::read(body, content_length, buffer_limit).await {
+        Ok(body) => body,
+        Err(e) => {
+            eprintln!("读取请求体失败: {}", e);
+            return error_response(400, "Bad Request");
+        }
+    };
+
+    // 直接转发时使用默认的超时设置
+    let timeouts = {
+        let config = CONFIG.read().unwrap();
+        match find_upstream_group(&config, &location.upstream) {
+            Some(upstream) if !location.upstream.is_empty() => ProxyTimeouts::new(upstream),
+            _ => ProxyTimeouts::new(&UpstreamSection::default()),
+        }
+    };
+    let client = shared_client(&location.upstream, &timeouts);
+
+    // 未配置上游服务器组时直接转发到 proxy_pass 或 server.backend_addr
+    if location.upstream.is_empty() {
+        let upstream_addr = if location.proxy_pass.is_empty() {
+            CONFIG.read().unwrap().server.backend_addr.clone()
+        } else {
+            location.proxy_pass.clone()
+        };
+        let upstream_addr = upstream_addr.trim_start_matches("http://").trim_end_matches('/').to_string();
+
+        return match send(&client, &upstream_addr, &parts, location.preserve_host, body, &timeouts).await {
+            Ok(upstream_response) => relay_response(upstream_response, timeouts.read, None),
+            Err(e) => {
+                eprintln!("转发请求到 {} 失败: {}", upstream_addr, e);
+                e.to_response()
+            }
+        };
+    }
+
+    let mut tried: Vec<String> = Vec::new();
+    let mut body = Some(body);
+    let mut last_error: Option<ProxyError> = None;
+    // 因状态码触发重试的最后一个响应，没有其他服务器可用时返回给客户端
+    let mut last_response: Option<Response<Body>> = None;
+
+    loop {
+        // 按配置的负载均衡算法选择尚未尝试过的可用上游服务器
+        let selected = {
+            let config = CONFIG.read().unwrap();
+            find_upstream_group(&config, &location.upstream).and_then(|upstream| {
+                balancer::select_upstream(
+                    &location.upstream,
+                    upstream,
+                    config.features.load_balancing,
+                    conn.remote_addr.ip(),
+                    &tried,
+                )
+            })
+        };
+        let selected = match selected {
+            Some(selected) => selected,
+            None => {
+                eprintln!("没有可用的上游服务器，已尝试: {:?}", tried);
+                break;
+            }
+        };
+        let upstream_addr = selected.address.clone();
+        tried.push(upstream_addr.clone());
+
+        // 缓存的请求体可以重复发送，流式请求体只能发送一次
+        let attempt_body = match body.take() {
+            Some(RequestBody::Buffered(bytes)) => {
+                body = Some(RequestBody::Buffered(bytes.clone()));
+                RequestBody::Buffered(bytes)
+            }
+            Some(streaming) => streaming,
+            None => break,
+        };
+        let can_retry = retry
+            && body.is_some()
+            && (location.proxy_next_upstream_tries == 0 || tried.len() < location.proxy_next_upstream_tries as usize);
+
+        match send(&client, &upstream_addr, &parts, location.preserve_host, attempt_body, &timeouts).await {
+            Ok(upstream_response) => {
+                let status = upstream_response.status().as_u16();
+                if can_retry && retry_on_status(location, status) {
+                    eprintln!("上游服务器 {} 返回状态码 {}，尝试下一个服务器", upstream_addr, status);
+                    // 与 nginx 相同，403、404 不计入失败次数
+                    if status == 403 || status == 404 {
+                        selected.report_success();
+                    } else {
+                        selected.report_failure();
+                    }
+                    last_response = Some(relay_response(upstream_response, timeouts.read, Some(selected)));
+                    last_error = None;
+                    continue;
+                }
+                selected.report_success();
+                // 返回上游服务器的响应，响应体传输完成前保持该服务器的活动连接计数
+                return relay_response(upstream_response, timeouts.read, Some(selected));
+            }
+            Err(e) => {
+                eprintln!("转发请求到上游服务器 {} 失败: {}", upstream_addr, e);
+                selected.report_failure();
+                let retry_error = can_retry && retry_on_error(location, &e);
+                last_error = Some(e);
+                last_response = None;
+                if !retry_error {
+                    break;
+                }
+            }
+        }
+    }
+
+    if let Some(response) = last_response {
+        return response;
+    }
+    // 与 nginx 相同，最后一次失败是超时时返回 504
+    match last_error {
+        Some(e) => e.to_response(),
+        None => ProxyError::Failed("没有可用的上游服务器".to_string()).to_response(),
+    }
+}
+
+// 构造转发请求并发送到指定的上游地址
+//
+// 连接超时由客户端的连接器控制；请求体按块发送，每块超过 send 超时未被上游读取时中止；
+// 请求完整写入上游后开始计算 read 超时，等待响应头超过 read 超时时中止。
+async fn send(
+    client: &Client<HttpConnector>,
+    upstream_addr: &str,
+    parts: &Parts,
+    preserve_host: bool,
+    body: RequestBody,
+    timeouts: &ProxyTimeouts,
+) -> Result<Response<Body>, ProxyError> {
+    // 构造转发URL
+    let forward_url = format!(
+        "http://{}{}",
+        upstream_addr,
+        parts.uri.path_and_query().map(|p| p.as_str()).unwrap_or("")
+    );
+
+    let send_timed_out = Arc::new(AtomicBool::new(false));
+    // 缓存的请求体使用确定的长度发送，流式请求体沿用客户端的 Content-Length
+    let body_length = match &body {
+        RequestBody::Buffered(bytes) => Some(bytes.len()),
+        RequestBody::Streaming(..) => None,
+    };
+    // 请求体发送完成时通知，没有请求体时立即通知
+    let (written_tx, written_rx) = tokio::sync::oneshot::channel();
+    let request_body = match body {
+        RequestBody::Buffered(bytes) if bytes.is_empty() => {
+            drop(written_tx);
+            Body::empty()
+        }
+        RequestBody::Buffered(bytes) => send_body(bytes, None, timeouts.send, send_timed_out.clone(), written_tx),
+        RequestBody::Streaming(prefix, rest) => {
+            send_body(prefix, Some(rest), timeouts.send, send_timed_out.clone(), written_tx)
+        }
+    };
+
+    let mut forward_req = Request::builder()
+        .method(parts.method.clone())
+        .uri(&forward_url)
+        .body(request_body)
+        .unwrap();
+
+    // 复制头部信息，同名的多个头部全部保留
+    for (name, value) in parts.headers.iter() {
+        // 除非配置了 preserve_host，否则不转发原始的Host头
+        if name != hyper::header::HOST || preserve_host {
+            forward_req.headers_mut().append(name, value.clone());
+        }
+    }
+
+    match body_length {
+        Some(0) => {
+            forward_req.headers_mut().remove(hyper::header::CONTENT_LENGTH);
+        }
+        Some(length) => {
+            forward_req
+                .headers_mut()
+                .insert(hyper::header::CONTENT_LENGTH, HeaderValue::from(length));
+        }
+        None => {}
+    }
+
+    // 设置正确的Host头为上游服务器地址
+    if !preserve_host || !forward_req.headers().contains_key(hyper::header::HOST) {
+        if let Ok(host_header) = HeaderValue::from_str(upstream_addr) {
+            forward_req.headers_mut().insert(hyper::header::HOST, host_header);
+        }
+    }
+
+    let mut connection = capture_connection(&mut forward_req);
+    let response = client.request(forward_req);
+    tokio::pin!(response);
+    let result = tokio::select! {
+        // 连接失败，或上游在请求体发送完之前就返回了响应
+        result = &mut response => Ok(result),
+        // 连接已建立 (或从连接池取得) 且请求已写入，开始等待响应头
+        _ = async {
+            connection.wait_for_connection_metadata().await;
+            let _ = written_rx.await;
+        } => tokio::time::timeout(timeouts.read, &mut response).await,
+    };
+
+    let mut response = match result {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => {
+            if send_timed_out.load(Ordering::Relaxed) {
+                return Err(ProxyError::Timeout(format!("发送请求体超时 ({:?})", timeouts.send)));
+            }
+            if is_timeout(&e) {
+                return Err(ProxyError::Timeout(format!("连接超时 ({:?}): {}", timeouts.connect, e)));
+            }
+            return Err(ProxyError::Failed(e.to_string()));
+        }
+        Err(_) => return Err(ProxyError::Timeout(format!("等待上游响应超时 ({:?})", timeouts.read))),
+    };
+    remove_hop_by_hop_headers(response.headers_mut());
+    Ok(response)
+}
+
+// 将请求体按块写入发送给上游的请求，每块超过 send 超时未被上游读取时中止请求
+fn send_body(
+    prefix: Bytes,
+    rest: Option<Body>,
+    send_timeout: Duration,
+    timed_out: Arc<AtomicBool>,
+    written: tokio::sync::oneshot::Sender<()>,
+) -> Body {
+    let (mut sender, request_body) = Body::channel();
+    tokio::spawn(async move {
+        let mut rest = rest;
+        let mut pending = prefix;
+        loop {
+            while !pending.is_empty() {
+                let chunk = pending.split_to(SEND_CHUNK_SIZE.min(pending.len()));
+                match tokio::time::timeout(send_timeout, sender.send_data(chunk)).await {
+                    Ok(Ok(())) => {}
+                    // 请求已结束，例如上游提前返回了响应
+                    Ok(Err(_)) => return,
+                    Err(_) => {
+                        timed_out.store(true, Ordering::Relaxed);
+                        sender.abort();
+                        return;
+                    }
+                }
+            }
+            // 继续读取客户端尚未发送完的请求体
+            pending = match rest.as_mut() {
+                Some(body) => match body.data().await {
+                    Some(Ok(chunk)) => chunk,
+                    Some(Err(e)) => {
+                        eprintln!("读取请求体失败: {}", e);
+                        sender.abort();
+                        return;
+                    }
+                    None => break,
+                },
+                None => break,
+            };
+        }
+        let _ = written.send(());
+    });
+    request_body
+}
+
+// 检查错误链中是否有超时错误，连接器的连接超时以 io::ErrorKind::TimedOut 报告
+fn is_timeout(error: &hyper::Error) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = std::error::Error::source(error);
+    while let Some(e) = source {
+        if let Some(io_error) = e.downcast_ref::<std::io::Error>() {
+            if io_error.kind() == std::io::ErrorKind::TimedOut {
+                return true;
+            }
+        }
+        source = e.source();
+    }
+    false
+}
+
+// 将上游响应体转发到新的响应中。相邻两次读取超过 read 超时时中止响应，
+// 直到响应体传输完成才释放上游服务器的活动连接计数。
+fn relay_response(response: Response<Body>, read_timeout: Duration, peer: Option<SelectedPeer>) -> Response<Body> {
+    let (parts, mut upstream_body) = response.into_parts();
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        let _peer = peer;
+        loop {
+            let chunk = match tokio::time::timeout(read_timeout, upstream_body.data()).await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                Err(_) => {
+                    eprintln!("读取上游响应体超时 ({:?})", read_timeout);
+                    sender.abort();
+                    return;
+                }
+            };
+            match chunk {
+                Ok(chunk) => {
+                    // 客户端已断开
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    eprintln!("读取上游响应体失败: {}", e);
+                    sender.abort();
+                    return;
+                }
+            }
+        }
+        if let Ok(Some(trailers)) = upstream_body.trailers().await {
+            let _ = sender.send_trailers(trailers).await;
+        }
+    });
+
+    Response::from_parts(parts, body)
+}
+
+// 逐跳头部只对单个连接有意义，代理在两个方向上都不能转发 (RFC 9110 第 7.6.1 节)
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "proxy-connection",
+    "keep-alive",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "proxy-authenticate",
+    "proxy-authorization",
+];
+
+// 移除逐跳头部以及 Connection 头中列出的头部
+fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    let listed: Vec<String> = headers
+        .get_all(hyper::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+
+    for name in listed.iter().map(|name| name.as_str()).chain(HOP_BY_HOP_HEADERS) {
+        headers.remove(name);
+    }
+}
+
+// 按 location 的 proxy_headers 配置添加 X-Forwarded-*、X-Real-IP、Forwarded 和 Via 头
+fn add_proxy_headers(headers: &mut HeaderMap, uri: &Uri, location: &LocationConfig, conn: &ConnectionInfo) {
+    let config = &location.proxy_headers;
+    let client_ip = conn.remote_addr.ip().to_string();
+    let proto = if conn.secure { "https" } else { "http" };
+    // HTTP/2 请求可能没有 Host 头，主机名在 URI 的 :authority 中
+    let host = headers
+        .get(hyper::header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| uri.authority().map(|authority| authority.as_str()))
+        .map(|host| host.to_string());
+
+    if config.x_forwarded_for {
+        append_header(headers, "x-forwarded-for", &client_ip);
+    }
+
+    if config.x_forwarded_proto {
+        set_header(headers, "x-forwarded-proto", proto);
+    }
+
+    if config.x_forwarded_host {
+        if let Some(host) = &host {
+            set_header(headers, "x-forwarded-host", host);
+        }
+    }
+
+    if config.x_real_ip {
+        set_header(headers, "x-real-ip", &client_ip);
+    }
+
+    if config.forwarded {
+        // RFC 7239: IPv6 地址需要加方括号并用引号包裹
+        let node = match conn.remote_addr.ip() {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+        };
+        let mut element = format!("for={}", node);
+        if let Some(host) = &host {
+            element.push_str(&format!(";host={}", quoted_string(host)));
+        }
+        element.push_str(&format!(";proto={}", proto));
+        append_header(headers, "forwarded", &element);
+    }
+
+    if config.via {
+        append_header(headers, "via", "1.1 rust-cool-nginx");
+    }
+
+    // 客户端自带的同名头部不可信，总是移除
+    headers.remove("x-client-cert-subject");
+    if config.x_client_cert_subject {
+        if let Some(subject) = &conn.client_cert_subject {
+            set_header(headers, "x-client-cert-subject", &subject.text);
+        }
+    }
+}
+
+// RFC 7230 的 quoted-string，转义其中的反斜杠与双引号
+fn quoted_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+// 将值追加到逗号分隔的列表头部之后，保留已有的代理链
+fn append_header(headers: &mut HeaderMap, name: &'static str, element: &str) {
+    let mut values: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+        .collect();
+    values.push(element);
+    let value = values.join(", ");
+    set_header(headers, name, &value);
+}
+
+fn set_header(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(name, value);
+    }
+}
+
+fn error_response(status: u16, message: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from(message))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::AtomicUsize;
+
+    use crate::test_util::spawn_server;
+
+    // 本地上游，把收到的请求头按 "name: value" 逐行返回，并在响应中带上逐跳头部
+    fn spawn_upstream() -> SocketAddr {
+        spawn_server(|req| async move {
+            let received: String = req
+                .headers()
+                .iter()
+                .map(|(name, value)| format!("{}: {}\n", name, value.to_str().unwrap_or("")))
+                .collect();
+            // 未设置 Content-Length 的流式响应体使用 Transfer-Encoding: chunked 发送
+            let body = Body::wrap_stream(futures_util::stream::once(async move { Ok::<_, Infallible>(received) }));
+            Response::builder()
+                .header("connection", "x-upstream-hop")
+                .header("x-upstream-hop", "1")
+                .header("keep-alive", "timeout=5")
+                .header("proxy-authenticate", "Basic")
+                .header("upgrade", "h2c")
+                .header("x-upstream-end-to-end", "1")
+                .body(body)
+                .unwrap()
+        })
+    }
+
+    fn location(proxy_pass: &str) -> LocationConfig {
+        serde_json::from_value(serde_json::json!({
+            "path": "/",
+            "handler": "proxy",
+            "proxy_pass": proxy_pass,
+            "proxy_headers": {
+                "x_forwarded_for": true, "x_forwarded_proto": true, "x_forwarded_host": true,
+                "x_real_ip": true, "forwarded": true, "via": true
+            }
+        }))
+        .unwrap()
+    }
+
+    fn conn() -> ConnectionInfo {
+        ConnectionInfo {
+            remote_addr: "192.0.2.10:50000".parse().unwrap(),
+            secure: true,
+            client_cert_subject: None,
+            server_name: None,
+        }
+    }
+
+    // 上游收到的请求头
+    fn received_headers(body: &str) -> HashMap<String, String> {
+        body.lines()
+            .filter_map(|line| line.split_once(": "))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn hop_by_hop_headers_removed_in_both_directions() {
+        let upstream = spawn_upstream();
+        let req = Request::get("/page")
+            .header("host", "example.com")
+            .header("connection", "keep-alive, x-client-hop")
+            .header("x-client-hop", "1")
+            .header("keep-alive", "timeout=5")
+            .header("te", "trailers")
+            .header("upgrade", "websocket")
+            .header("proxy-authorization", "Basic dXNlcjpwYXNz")
+            .header("transfer-encoding", "chunked")
+            .header("x-forwarded-for", "203.0.113.7")
+            .header("x-end-to-end", "1")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = forward(req, &conn(), &location(&format!("http://{}", upstream))).await;
+        assert_eq!(response.status(), 200);
+
+        for name in ["connection", "x-upstream-hop", "keep-alive", "proxy-authenticate", "upgrade", "transfer-encoding"] {
+            assert!(!response.headers().contains_key(name), "响应中仍有 {}", name);
+        }
+        assert_eq!(response.headers()["x-upstream-end-to-end"], "1");
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let received = received_headers(std::str::from_utf8(&body).unwrap());
+        for name in ["x-client-hop", "keep-alive", "te", "upgrade", "proxy-authorization", "transfer-encoding"] {
+            assert!(!received.contains_key(name), "上游收到了 {}", name);
+        }
+        assert!(!received.get("connection").is_some_and(|value| value.contains("x-client-hop")));
+        assert_eq!(received["x-end-to-end"], "1");
+
+        assert_eq!(received["x-forwarded-for"], "203.0.113.7, 192.0.2.10");
+        assert_eq!(received["x-forwarded-proto"], "https");
+        assert_eq!(received["x-forwarded-host"], "example.com");
+        assert_eq!(received["x-real-ip"], "192.0.2.10");
+        assert_eq!(received["forwarded"], "for=192.0.2.10;host=\"example.com\";proto=https");
+        assert_eq!(received["via"], "1.1 rust-cool-nginx");
+        assert_eq!(received["host"], upstream.to_string());
+    }
+
+    // 按路径模拟不同的上游: /slow-headers 读完请求体 600ms 后才返回响应头，
+    // /drip 每 200ms 发送一块响应体，其余路径读完请求体后立即返回收到的字节数
+    fn spawn_timing_upstream() -> SocketAddr {
+        spawn_server(|req| async move {
+            let path = req.uri().path().to_string();
+            let received = hyper::body::to_bytes(req.into_body()).await.unwrap().len();
+            let body = match path.as_str() {
+                "/slow-headers" => {
+                    tokio::time::sleep(Duration::from_millis(600)).await;
+                    Body::from(received.to_string())
+                }
+                "/drip" => Body::wrap_stream(futures_util::stream::unfold(0, |sent| async move {
+                    if sent == 4 {
+                        return None;
+                    }
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    Some((Ok::<_, Infallible>("x"), sent + 1))
+                })),
+                _ => Body::from(received.to_string()),
+            };
+            Response::new(body)
+        })
+    }
+
+    // 每 150ms 发送一块的请求体，共 600ms，超过 read 超时
+    fn slow_request_body() -> Body {
+        Body::wrap_stream(futures_util::stream::unfold(0, |sent| async move {
+            if sent == 4 {
+                return None;
+            }
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            Some((Ok::<_, Infallible>("abcd"), sent + 1))
+        }))
+    }
+
+    fn timeouts() -> ProxyTimeouts {
+        ProxyTimeouts {
+            connect: Duration::from_secs(1),
+            read: Duration::from_millis(400),
+            send: Duration::from_secs(1),
+            keepalive: 0,
+            keepalive_timeout: Duration::from_secs(1),
+        }
+    }
+
+    async fn send_to(upstream: SocketAddr, path: &str, body: RequestBody) -> Result<Response<Body>, ProxyError> {
+        let timeouts = timeouts();
+        let client = shared_client("test_timeouts", &timeouts);
+        let (parts, _) = Request::post(path).body(()).unwrap().into_parts();
+        send(&client, &upstream.to_string(), &parts, false, body, &timeouts).await
+    }
+
+    #[tokio::test]
+    async fn read_timeout_starts_after_request_is_written() {
+        let upstream = spawn_timing_upstream();
+
+        // 发送请求体的时间不计入 read 超时
+        let response = send_to(upstream, "/upload", RequestBody::Streaming(Bytes::new(), slow_request_body()))
+            .await
+            .unwrap_or_else(|e| panic!("{}", e));
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"16");
+
+        // 请求写入后等待响应头超过 read 超时
+        let result = send_to(upstream, "/slow-headers", RequestBody::Buffered(Bytes::from("abc"))).await;
+        assert!(matches!(result, Err(ProxyError::Timeout(_))));
+    }
+
+    #[test]
+    fn clients_of_removed_or_changed_groups_are_dropped() {
+        let upstream = UpstreamSection::default();
+        let timeouts = ProxyTimeouts::new(&upstream);
+        for group in ["test_sync_kept", "test_sync_changed", "test_sync_removed"] {
+            shared_client(group, &timeouts);
+        }
+        let changed = UpstreamSection {
+            proxy_read_timeout: "5s".to_string(),
+            ..UpstreamSection::default()
+        };
+
+        sync_clients(&[
+            ("test_sync_kept".to_string(), upstream),
+            ("test_sync_changed".to_string(), changed),
+        ]);
+        let clients = CLIENTS.lock().unwrap();
+        assert!(clients.contains_key("test_sync_kept"));
+        assert!(!clients.contains_key("test_sync_changed"));
+        assert!(!clients.contains_key("test_sync_removed"));
+    }
+
+    #[tokio::test]
+    async fn read_timeout_applies_between_body_chunks() {
+        let upstream = spawn_timing_upstream();
+
+        // 总时长超过 read 超时，但相邻两块的间隔没有超过
+        let response = send_to(upstream, "/drip", RequestBody::Buffered(Bytes::new())).await.unwrap_or_else(|e| panic!("{}", e));
+        let response = relay_response(response, Duration::from_millis(400), None);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"xxxx");
+
+        // 相邻两块的间隔超过 read 超时时中止响应体
+        let response = send_to(upstream, "/drip", RequestBody::Buffered(Bytes::new())).await.unwrap_or_else(|e| panic!("{}", e));
+        let response = relay_response(response, Duration::from_millis(100), None);
+        assert!(hyper::body::to_bytes(response.into_body()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn legacy_single_upstream_config_routes_to_default_group() {
+        let upstream = spawn_server(|req| async move { Response::new(Body::from(format!("legacy {}", req.uri()))) });
+
+        // 旧版配置只有 upstream，没有 upstreams 与 locations
+        let mut legacy = serde_json::to_value(crate::default_config()).unwrap();
+        legacy.as_object_mut().unwrap().remove("upstreams");
+        legacy.as_object_mut().unwrap().remove("locations");
+        legacy["upstream"]["servers"] = serde_json::json!([
+            {"address": upstream.to_string(), "weight": 1, "max_fails": 3, "fail_timeout": "10s"}
+        ]);
+        let legacy: crate::ServerConfig = serde_json::from_value(legacy).unwrap();
+        crate::validate_config(&legacy).unwrap();
+        assert!(legacy.upstreams.is_empty());
+
+        // 默认的 /admin-api/ location 转发到 default 组，即旧版的 upstream
+        let location = crate::location::find(&legacy.locations, "/admin-api/users").unwrap().clone();
+        assert_eq!(location.upstream, crate::DEFAULT_UPSTREAM_GROUP);
+        CONFIG.write().unwrap().upstream = legacy.upstream.clone();
+        let req = Request::get("/admin-api/users").body(Body::empty()).unwrap();
+        let response = forward(req, &conn(), &location).await;
+        assert_eq!(response.status(), 200);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"legacy /admin-api/users");
+    }
+
+    // 本地上游，读完请求体后以 status 返回 "名称 收到的字节数"，并统计收到的请求数
+    fn spawn_peer(name: &'static str, status: u16) -> (String, Arc<AtomicUsize>) {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let addr = spawn_server(move |req: Request<Body>| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                let received = hyper::body::to_bytes(req.into_body()).await.unwrap().len();
+                Response::builder()
+                    .status(status)
+                    .body(Body::from(format!("{} {}", name, received)))
+                    .unwrap()
+            }
+        });
+        (addr.to_string(), hits)
+    }
+
+    // 没有监听的地址，连接会被拒绝
+    fn refused_addr() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    // 在全局配置中添加服务器组 group，返回转发到该组的 location，options 覆盖 location 的默认配置
+    fn group_location(group: &str, servers: &[&str], options: serde_json::Value) -> LocationConfig {
+        let servers: Vec<serde_json::Value> = servers
+            .iter()
+            .map(|address| serde_json::json!({"address": address, "weight": 1, "max_fails": 0, "fail_timeout": "10s"}))
+            .collect();
+        let upstream: UpstreamSection =
+            serde_json::from_value(serde_json::json!({"load_balancing_algorithm": "round_robin", "servers": servers}))
+                .unwrap();
+        CONFIG.write().unwrap().upstreams.insert(group.to_string(), upstream);
+
+        let mut location = serde_json::json!({"path": "/", "handler": "proxy", "upstream": group});
+        location.as_object_mut().unwrap().extend(options.as_object().unwrap().clone());
+        serde_json::from_value(location).unwrap()
+    }
+
+    async fn request(method: Method, location: &LocationConfig, body: &'static str) -> (u16, String) {
+        let req = Request::builder().method(method).uri("/retry").body(Body::from(body)).unwrap();
+        let response = forward(req, &conn(), location).await;
+        let status = response.status().as_u16();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn get_retried_on_next_upstream_after_502() {
+        let (bad, bad_hits) = spawn_peer("bad", 502);
+        let (good, _) = spawn_peer("good", 200);
+        let location = group_location("test_retry_502", &[&bad, &good], serde_json::json!({
+            "proxy_next_upstream": ["error", "http_502"]
+        }));
+        assert_eq!(request(Method::GET, &location, "").await, (200, "good 0".to_string()));
+        assert_eq!(bad_hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn get_retried_on_next_upstream_after_refused_connection() {
+        let (good, good_hits) = spawn_peer("good", 200);
+        // 默认的 proxy_next_upstream 包含 error
+        let location = group_location("test_retry_refused", &[&refused_addr(), &good], serde_json::json!({}));
+        assert_eq!(request(Method::GET, &location, "").await, (200, "good 0".to_string()));
+        assert_eq!(good_hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn post_is_not_retried() {
+        let (bad, _) = spawn_peer("bad", 502);
+        let (good, good_hits) = spawn_peer("good", 200);
+        let location = group_location("test_retry_post", &[&bad, &good], serde_json::json!({
+            "proxy_next_upstream": ["error", "http_502"]
+        }));
+        assert_eq!(request(Method::POST, &location, "data").await, (502, "bad 4".to_string()));
+        assert_eq!(good_hits.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn retries_stop_after_proxy_next_upstream_tries() {
+        let peers: Vec<(String, Arc<AtomicUsize>)> =
+            ["a", "b", "c"].into_iter().map(|name| spawn_peer(name, 502)).collect();
+        let servers: Vec<&str> = peers.iter().map(|(address, _)| address.as_str()).collect();
+        let location = group_location("test_retry_tries", &servers, serde_json::json!({
+            "proxy_next_upstream": ["http_502"], "proxy_next_upstream_tries": 2
+        }));
+        assert_eq!(request(Method::GET, &location, "").await, (502, "b 0".to_string()));
+        let hits: Vec<usize> = peers.iter().map(|(_, hits)| hits.load(Ordering::SeqCst)).collect();
+        assert_eq!(hits, [1, 1, 0]);
+    }
+
+    #[tokio::test]
+    async fn body_over_buffer_limit_is_not_replayed() {
+        let (bad, _) = spawn_peer("bad", 502);
+        let (good, good_hits) = spawn_peer("good", 200);
+        let location = group_location("test_retry_body", &[&bad, &good], serde_json::json!({
+            "proxy_next_upstream": ["http_502"], "proxy_next_upstream_body_size": 8
+        }));
+        // 超过缓存上限的请求体只能发送一次
+        assert_eq!(request(Method::PUT, &location, "0123456789").await, (502, "bad 10".to_string()));
+        assert_eq!(good_hits.load(Ordering::SeqCst), 0);
+
+        // 未超过上限的请求体完整地重新发送
+        assert_eq!(request(Method::PUT, &location, "01234567").await, (200, "good 8".to_string()));
+    }
+
+    #[test]
+    fn forwarded_host_from_authority_without_host_header() {
+        // HTTP/2 请求没有 Host 头
+        let uri: Uri = "https://h2.example.com:8443/page".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        add_proxy_headers(&mut headers, &uri, &location("http://127.0.0.1:1"), &conn());
+        assert_eq!(headers["x-forwarded-host"], "h2.example.com:8443");
+        assert_eq!(headers["forwarded"], "for=192.0.2.10;host=\"h2.example.com:8443\";proto=https");
+    }
+
+    #[test]
+    fn forwarded_host_is_quoted_and_escaped() {
+        let uri: Uri = "/".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("host", HeaderValue::from_static("a\"b\\c"));
+        add_proxy_headers(&mut headers, &uri, &location("http://127.0.0.1:1"), &conn());
+        assert_eq!(headers["forwarded"], "for=192.0.2.10;host=\"a\\\"b\\\\c\";proto=https");
+
+        let mut ipv6 = conn();
+        ipv6.remote_addr = "[2001:db8::1]:443".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        add_proxy_headers(&mut headers, &uri, &location("http://127.0.0.1:1"), &ipv6);
+        assert_eq!(headers["forwarded"], "for=\"[2001:db8::1]\";proto=https");
+    }
+}
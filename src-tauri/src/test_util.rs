@@ -0,0 +1,29 @@
+// 测试共用的本地 HTTP 服务器
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+
+/// 在 127.0.0.1 的随机端口上启动 HTTP 服务器，每个请求交给 `handler` 处理，返回监听地址
+pub(crate) fn spawn_server<F, R>(handler: F) -> SocketAddr
+where
+    F: Fn(Request<Body>) -> R + Clone + Send + Sync + 'static,
+    R: Future<Output = Response<Body>> + Send + 'static,
+{
+    let make = make_service_fn(move |_| {
+        let handler = handler.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let response = handler(req);
+                async move { Ok::<_, Infallible>(response.await) }
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
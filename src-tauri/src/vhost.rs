@@ -0,0 +1,369 @@
+// 基于名称的虚拟主机
+//
+// 启用 features.virtual_hosts 后按请求的 Host 头 (HTTP/2 为 URI 中的主机名，都没有时使用 TLS SNI)
+// 选择虚拟主机，匹配顺序与 nginx 相同：完全匹配、最长的前缀通配符 (*.example.com)、
+// 最长的后缀通配符 (www.example.*)、按配置顺序的正则 (~ 开头)。都不匹配时使用 default_server，
+// 没有 default_server 时使用顶层的 server 与 locations 配置。
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Request, Response};
+
+use crate::{location, ConnectionInfo, ServerConfig, VirtualHostConfig, VirtualHostLimits};
+
+// 请求频率限制的统计窗口
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+// 各虚拟主机当前统计窗口的开始时间与请求数
+lazy_static::lazy_static! {
+    static ref RATE_COUNTERS: Mutex<HashMap<String, (Instant, u32)>> = Mutex::new(HashMap::new());
+}
+
+/// 处理请求时使用的虚拟主机设置，未匹配虚拟主机时为顶层 server 的设置
+pub(crate) struct Site {
+    // 虚拟主机的第一个 server_name，顶层 server 为空
+    pub(crate) name: String,
+    // 虚拟主机自己的静态文件根目录
+    pub(crate) static_root: Option<String>,
+    pub(crate) access_log: String,
+    pub(crate) error_log: String,
+    limits: VirtualHostLimits,
+}
+
+impl Site {
+    pub(crate) fn new(config: &ServerConfig, vhost: Option<&VirtualHostConfig>) -> Self {
+        let server = &config.server;
+        match vhost {
+            Some(vhost) => {
+                let or_server = |value: &str, fallback: &str| {
+                    if value.is_empty() {
+                        fallback.to_string()
+                    } else {
+                        value.to_string()
+                    }
+                };
+                Site {
+                    name: vhost.server_names.first().cloned().unwrap_or_default(),
+                    static_root: Some(vhost.static_root.clone()).filter(|root| !root.is_empty()),
+                    access_log: or_server(&vhost.access_log, &server.access_log),
+                    error_log: or_server(&vhost.error_log, &server.error_log),
+                    limits: vhost.limits.clone(),
+                }
+            }
+            None => Site {
+                name: String::new(),
+                static_root: None,
+                access_log: server.access_log.clone(),
+                error_log: server.error_log.clone(),
+                limits: VirtualHostLimits::default(),
+            },
+        }
+    }
+
+    /// 检查请求体大小与请求频率限制，超出时返回错误响应
+    pub(crate) fn check_limits(&self, req: &Request<Body>) -> Option<Response<Body>> {
+        let limits = &self.limits;
+        if limits.client_max_body_size > 0 {
+            let content_length = req
+                .headers()
+                .get(hyper::header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok());
+            if content_length.is_some_and(|length| length > limits.client_max_body_size) {
+                return Some(limit_response(413, "Payload Too Large"));
+            }
+        }
+
+        if limits.max_requests_per_minute > 0 {
+            let mut counters = RATE_COUNTERS.lock().unwrap();
+            let now = Instant::now();
+            let (window_start, count) = counters.entry(self.name.clone()).or_insert((now, 0));
+            if now.duration_since(*window_start) >= RATE_WINDOW {
+                *window_start = now;
+                *count = 0;
+            }
+            if *count >= limits.max_requests_per_minute {
+                return Some(limit_response(429, "Too Many Requests"));
+            }
+            *count += 1;
+        }
+        None
+    }
+}
+
+fn limit_response(status: u16, message: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from(message))
+        .unwrap()
+}
+
+/// 请求的主机名，去掉端口并转为小写
+pub(crate) fn request_host(req: &Request<Body>, conn: &ConnectionInfo) -> Option<String> {
+    let host = req
+        .headers()
+        .get(hyper::header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| req.uri().authority().map(|authority| authority.as_str()))
+        .map(strip_port)
+        .or(conn.server_name.as_deref())?;
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    if host.is_empty() {
+        None
+    } else {
+        Some(host)
+    }
+}
+
+fn strip_port(host: &str) -> &str {
+    // IPv6 地址如 [::1]:8080
+    if host.starts_with('[') {
+        return match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        };
+    }
+    match host.rfind(':') {
+        Some(colon) => &host[..colon],
+        None => host,
+    }
+}
+
+/// 按主机名查找虚拟主机，未启用虚拟主机或没有匹配且没有 default_server 时返回 None
+pub(crate) fn find<'a>(config: &'a ServerConfig, host: Option<&str>) -> Option<&'a VirtualHostConfig> {
+    if !config.features.virtual_hosts || config.virtual_hosts.is_empty() {
+        return None;
+    }
+    let vhosts = &config.virtual_hosts;
+    let default_server = vhosts.iter().find(|vhost| vhost.default_server);
+    let host = match host {
+        Some(host) => host,
+        None => return default_server,
+    };
+
+    let names = || {
+        vhosts
+            .iter()
+            .flat_map(|vhost| vhost.server_names.iter().map(move |name| (vhost, name.as_str())))
+    };
+
+    if let Some((vhost, _)) = names().find(|(_, name)| !name.starts_with('~') && !name.contains('*') && name.eq_ignore_ascii_case(host)) {
+        return Some(vhost);
+    }
+
+    // 最长的前缀通配符，"*.example.com" 匹配 "www.example.com" 与 "a.b.example.com"
+    let leading = names()
+        .filter_map(|(vhost, name)| name.strip_prefix('*').map(|suffix| (vhost, suffix)))
+        .filter(|(_, suffix)| host.len() > suffix.len() && ends_with_ignore_case(host, suffix))
+        .max_by_key(|(_, suffix)| suffix.len());
+    if let Some((vhost, _)) = leading {
+        return Some(vhost);
+    }
+
+    // 最长的后缀通配符，"www.example.*" 匹配 "www.example.com"
+    let trailing = names()
+        .filter_map(|(vhost, name)| name.strip_suffix('*').map(|prefix| (vhost, prefix)))
+        .filter(|(_, prefix)| host.len() > prefix.len() && starts_with_ignore_case(host, prefix))
+        .max_by_key(|(_, prefix)| prefix.len());
+    if let Some((vhost, _)) = trailing {
+        return Some(vhost);
+    }
+
+    for vhost in vhosts {
+        if server_name_regexes(vhost).iter().flatten().any(|regex| regex.is_match(host)) {
+            return Some(vhost);
+        }
+    }
+
+    default_server
+}
+
+// server_names 中以 ~ 开头的正则，按配置顺序
+fn server_name_regexes(vhost: &VirtualHostConfig) -> &[Result<regex::Regex, String>] {
+    vhost
+        .server_name_regexes
+        .get(vhost.server_names.iter().filter_map(|name| name.strip_prefix('~')))
+}
+
+fn ends_with_ignore_case(value: &str, suffix: &str) -> bool {
+    value.len() >= suffix.len()
+        && value.is_char_boundary(value.len() - suffix.len())
+        && value[value.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+}
+
+fn starts_with_ignore_case(value: &str, prefix: &str) -> bool {
+    value.len() >= prefix.len() && value.is_char_boundary(prefix.len()) && value[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
+/// 校验虚拟主机配置
+pub(crate) fn validate(config: &ServerConfig) -> Result<(), String> {
+    if config.virtual_hosts.iter().filter(|vhost| vhost.default_server).count() > 1 {
+        return Err("只能有一个虚拟主机设置为 default_server".to_string());
+    }
+    for vhost in &config.virtual_hosts {
+        if vhost.server_names.is_empty() {
+            return Err("虚拟主机必须配置 server_names".to_string());
+        }
+        if let Some(Err(e)) = server_name_regexes(vhost).iter().find(|regex| regex.is_err()) {
+            return Err(e.clone());
+        }
+        for name in &vhost.server_names {
+            if name.starts_with('~') {
+                continue;
+            }
+            let valid = match name.matches('*').count() {
+                0 => !name.is_empty(),
+                1 => (name.starts_with("*.") && name.len() > 2) || (name.ends_with(".*") && name.len() > 2),
+                _ => false,
+            };
+            if !valid {
+                return Err(format!(
+                    "虚拟主机的 server_name 无效: {}，可用形式: example.com、*.example.com、www.example.*、~正则",
+                    name
+                ));
+            }
+        }
+        location::validate_locations(config, &vhost.locations)
+            .map_err(|e| format!("虚拟主机 {}: {}", vhost.server_names[0], e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(virtual_hosts: serde_json::Value) -> ServerConfig {
+        let mut config = crate::default_config();
+        config.features.virtual_hosts = true;
+        config.virtual_hosts = serde_json::from_value(virtual_hosts).unwrap();
+        config
+    }
+
+    fn found(config: &ServerConfig, host: Option<&str>) -> Option<String> {
+        find(config, host).map(|vhost| vhost.server_names[0].clone())
+    }
+
+    fn conn(server_name: Option<&str>) -> ConnectionInfo {
+        ConnectionInfo {
+            remote_addr: "127.0.0.1:50000".parse().unwrap(),
+            secure: server_name.is_some(),
+            client_cert_subject: None,
+            server_name: server_name.map(|name| name.to_string()),
+        }
+    }
+
+    #[test]
+    fn server_names_matched_in_nginx_order() {
+        let vhosts = config(serde_json::json!([
+            {"server_names": ["~^api\\d+\\.example\\.com$"]},
+            {"server_names": ["www.example.*"]},
+            {"server_names": ["www.example.com", "example.com"]},
+            {"server_names": ["*.example.com"]},
+            {"server_names": ["*.static.example.com"]},
+            {"server_names": ["www.*"]},
+            {"server_names": ["~^(?i)shop\\."]},
+        ]));
+
+        // 完全匹配优先于通配符与正则
+        assert_eq!(found(&vhosts, Some("www.example.com")).as_deref(), Some("www.example.com"));
+        assert_eq!(found(&vhosts, Some("EXAMPLE.com")).as_deref(), Some("www.example.com"));
+        // 最长的前缀通配符优先于后缀通配符与正则
+        assert_eq!(found(&vhosts, Some("api1.example.com")).as_deref(), Some("*.example.com"));
+        assert_eq!(found(&vhosts, Some("img.static.example.com")).as_deref(), Some("*.static.example.com"));
+        // 前缀通配符不匹配裸域名
+        assert_eq!(found(&vhosts, Some("static.example.com")).as_deref(), Some("*.example.com"));
+        // 最长的后缀通配符
+        assert_eq!(found(&vhosts, Some("www.example.org")).as_deref(), Some("www.example.*"));
+        assert_eq!(found(&vhosts, Some("www.test.org")).as_deref(), Some("www.*"));
+        // 正则按配置顺序匹配
+        assert_eq!(found(&vhosts, Some("api2.example.com.cn")), None);
+        assert_eq!(found(&vhosts, Some("SHOP.test.org")).as_deref(), Some("~^(?i)shop\\."));
+        assert_eq!(found(&vhosts, Some("other.test")), None);
+        assert_eq!(found(&vhosts, None), None);
+
+        // 没有通配符匹配时使用正则
+        let regex_only = config(serde_json::json!([
+            {"server_names": ["~^api\\d+\\.example\\.com$"]},
+            {"server_names": ["example.com"]},
+        ]));
+        assert_eq!(found(&regex_only, Some("api1.example.com")).as_deref(), Some("~^api\\d+\\.example\\.com$"));
+    }
+
+    #[test]
+    fn unmatched_host_uses_default_server() {
+        let mut config = config(serde_json::json!([
+            {"server_names": ["example.com"]},
+            {"server_names": ["fallback.test"], "default_server": true},
+        ]));
+        assert_eq!(found(&config, Some("example.com")).as_deref(), Some("example.com"));
+        assert_eq!(found(&config, Some("other.test")).as_deref(), Some("fallback.test"));
+        assert_eq!(found(&config, None).as_deref(), Some("fallback.test"));
+
+        // 未启用虚拟主机时使用顶层 server
+        config.features.virtual_hosts = false;
+        assert_eq!(found(&config, Some("example.com")), None);
+    }
+
+    #[test]
+    fn request_host_strips_port_and_falls_back_to_sni() {
+        let req = |host: Option<&str>, uri: &str| {
+            let mut builder = Request::builder().uri(uri);
+            if let Some(host) = host {
+                builder = builder.header(hyper::header::HOST, host);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let host = |req: Request<Body>, sni: Option<&str>| request_host(&req, &conn(sni));
+        assert_eq!(host(req(Some("Example.COM:8080"), "/"), None).as_deref(), Some("example.com"));
+        assert_eq!(host(req(Some("example.com."), "/"), None).as_deref(), Some("example.com"));
+        assert_eq!(host(req(Some("[::1]:8080"), "/"), None).as_deref(), Some("[::1]"));
+        assert_eq!(host(req(Some("[::1]"), "/"), None).as_deref(), Some("[::1]"));
+        // HTTP/2 请求使用 URI 中的主机名
+        assert_eq!(host(req(None, "https://www.example.com:8443/"), None).as_deref(), Some("www.example.com"));
+        // 都没有时使用 TLS SNI
+        assert_eq!(host(req(None, "/"), Some("sni.example.com")).as_deref(), Some("sni.example.com"));
+        assert_eq!(host(req(None, "/"), None), None);
+        assert_eq!(host(req(Some(":8080"), "/"), None), None);
+    }
+
+    #[test]
+    fn limits_reject_large_bodies_and_count_requests_per_site() {
+        let config = config(serde_json::json!([
+            {"server_names": ["limited.vhost.test"], "limits": {"client_max_body_size": 10, "max_requests_per_minute": 2}},
+            {"server_names": ["other.vhost.test"], "limits": {"max_requests_per_minute": 1}},
+        ]));
+        let limited = Site::new(&config, find(&config, Some("limited.vhost.test")));
+        let other = Site::new(&config, find(&config, Some("other.vhost.test")));
+        let status = |site: &Site, length: u64| {
+            let req = Request::builder()
+                .header(hyper::header::CONTENT_LENGTH, length)
+                .body(Body::empty())
+                .unwrap();
+            site.check_limits(&req).map(|response| response.status().as_u16())
+        };
+
+        // 超出大小的请求体不计入请求数
+        assert_eq!(status(&limited, 11), Some(413));
+        assert_eq!(status(&limited, 10), None);
+        assert_eq!(status(&limited, 0), None);
+        assert_eq!(status(&limited, 0), Some(429));
+        // 每个虚拟主机单独计数
+        assert_eq!(status(&other, 0), None);
+        assert_eq!(status(&other, 0), Some(429));
+
+        // 统计窗口结束后重新计数
+        RATE_COUNTERS.lock().unwrap().get_mut("limited.vhost.test").unwrap().0 -= RATE_WINDOW;
+        assert_eq!(status(&limited, 0), None);
+        assert_eq!(status(&limited, 0), None);
+        assert_eq!(status(&limited, 0), Some(429));
+
+        // 顶层 server 不限制
+        let top = Site::new(&config, None);
+        assert_eq!(status(&top, 1 << 30), None);
+    }
+}
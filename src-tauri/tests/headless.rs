@@ -0,0 +1,176 @@
+// 无界面服务器 cool-nginx 的冒烟测试：按配置文件启动、响应请求，收到 SIGTERM 后正常退出
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+const SERVER: &str = env!("CARGO_BIN_EXE_cool-nginx");
+
+// 每个测试使用单独的工作目录，日志等文件写在其中
+struct TestDir(PathBuf);
+
+impl TestDir {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("cool-nginx-headless-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        TestDir(dir)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// 结束时确保服务器进程退出
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+impl Server {
+    fn wait_timeout(&mut self, timeout: Duration) -> Option<ExitStatus> {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if let Some(status) = self.0.try_wait().unwrap() {
+                return Some(status);
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        None
+    }
+}
+
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+fn write_config(dir: &TestDir, port: u16) -> PathBuf {
+    let config = serde_json::json!({
+        "server": {
+            "listen_addr": format!("127.0.0.1:{}", port),
+            "backend_addr": "127.0.0.1:3000",
+            "static_root": "./public",
+            "access_log": "./logs/access.log",
+            "error_log": "./logs/error.log",
+            "log_level": "info",
+            "ssl_cert_path": "",
+            "ssl_key_path": "",
+            "ssl_enabled": false
+        },
+        "features": {
+            "static_file_serving": true,
+            "reverse_proxy": true,
+            "fastcgi_support": false,
+            "load_balancing": true,
+            "cache_enabled": false,
+            "cache_path": "./cache",
+            "cache_max_size": "100m",
+            "cache_inactive": "10m",
+            "gzip_compression": false,
+            "gzip_comp_level": 6,
+            "gzip_min_length": 1024,
+            "gzip_types": [],
+            "virtual_hosts": false,
+            "access_control": true,
+            "allow_ips": ["127.0.0.1"],
+            "deny_ips": [],
+            "rate_limiting": false,
+            "max_requests_per_minute": 1000,
+            "websocket_support": false,
+            "worker_processes": 1,
+            "worker_connections": 1024,
+            "monitoring_enabled": true,
+            "stats_path": "/stats"
+        }
+    });
+    let path = dir.0.join("nginx.conf");
+    std::fs::write(&path, serde_json::to_string_pretty(&config).unwrap()).unwrap();
+    path
+}
+
+// 发送 GET 请求，服务器还未开始监听时重试
+fn get(port: u16, path: &str) -> String {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut stream = loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => break stream,
+            Err(_) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(50)),
+            Err(e) => panic!("连接服务器失败: {}", e),
+        }
+    };
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn serve_answers_requests_and_stops_on_sigterm() {
+    let dir = TestDir::new("serve");
+    let port = free_port();
+    let config = write_config(&dir, port);
+    let server = Server(
+        Command::new(SERVER)
+            .args(["serve", "--config"])
+            .arg(&config)
+            .current_dir(&dir.0)
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap(),
+    );
+
+    let response = get(port, "/stats");
+    assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+    assert!(response.contains("\"total_requests\""), "{}", response);
+
+    #[cfg(unix)]
+    {
+        let mut server = server;
+        // 留出安装信号处理的时间
+        std::thread::sleep(Duration::from_millis(200));
+        let status = Command::new("kill")
+            .args(["-TERM", &server.0.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let status = server.wait_timeout(Duration::from_secs(10)).expect("服务器未在 10 秒内退出");
+        assert!(status.success(), "{:?}", status);
+
+        let mut stdout = String::new();
+        server.0.stdout.take().unwrap().read_to_string(&mut stdout).unwrap();
+        assert!(stdout.contains("收到 SIGTERM"), "{}", stdout);
+        assert!(stdout.contains("服务器已关闭"), "{}", stdout);
+        assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+    }
+}
+
+#[test]
+fn invalid_config_or_arguments_exit_with_error() {
+    let dir = TestDir::new("invalid");
+    let run = |args: &[&str]| {
+        Command::new(SERVER)
+            .args(args)
+            .current_dir(&dir.0)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap()
+            .code()
+    };
+
+    assert_eq!(run(&["serve", "--config", "missing.conf"]), Some(1));
+    std::fs::write(dir.0.join("broken.conf"), "{").unwrap();
+    assert_eq!(run(&["serve", "--config=broken.conf"]), Some(1));
+    assert_eq!(run(&["start"]), Some(2));
+    assert_eq!(run(&["serve", "--port", "80"]), Some(2));
+    assert_eq!(run(&["--help"]), Some(0));
+}